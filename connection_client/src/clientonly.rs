@@ -3,11 +3,11 @@ extern crate futures;
 extern crate bytes;
 extern crate console;
 
-use tokio::prelude::*;
 use std::string::String;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io::{BufReader, BufRead};
+use std::sync::Mutex;
 
 pub fn update() -> Result<(), Box<dyn ::std::error::Error>> {
     let current_version = env!("CARGO_PKG_VERSION");
@@ -30,7 +30,7 @@ pub fn update() -> Result<(), Box<dyn ::std::error::Error>> {
     Ok(())
 }
  
-pub fn read_config(file_path : &Path) -> (Option<String>, Option<String>) {
+pub fn read_config(file_path : &Path) -> (Option<String>, Option<String>, Vec<String>) {
     if file_path.exists() && file_path.is_file() {
         let file = File::open(&file_path).unwrap();
         let mut reader = BufReader::new(file);
@@ -54,77 +54,82 @@ pub fn read_config(file_path : &Path) -> (Option<String>, Option<String>) {
             },
             _ => None
         };
-        return (name, ip);
+
+        let mut highlights_str = String::new();
+        let _ = reader.read_line(&mut highlights_str);
+        let highlights = highlights_str.split_whitespace().map(|word| word.to_string()).collect();
+        return (name, ip, highlights);
     }
-    (None, None)
+    (None, None, Vec::new())
 }
 
-pub fn save_config(file_path : &Path, name : &String, ip : &String) {
-    let mut f = File::create(file_path).unwrap();
-    writeln!(&mut f, "{}", name).unwrap();
-    writeln!(&mut f, "{}", ip).unwrap();
-    let _ = f.sync_data();
-    println!(">>> config: {:?}", file_path);
+pub fn etags_path() -> PathBuf {
+    let mut file_path = dirs::data_local_dir().unwrap();
+    file_path.push("Connection.etags");
+    file_path
 }
 
-pub fn process_params() -> (String, String) {
-    let mut file_path = dirs::data_local_dir().unwrap();
-    file_path.push("Connection.cfg");
-    let (name_config, other_ip_config) = read_config(file_path.as_path());
-    let name = match std::env::args().nth(1) {
-        Some(val) => val,
-        None => match name_config {
-            Some(val) => val,
-            None => "UnnamedUser".to_string()
-        }
-    };
-    let ip = match std::env::args().nth(2) {
-        Some(val) => val,
-        None => match other_ip_config {
-            Some(val) => val,
-            None => "89.67.243.241".to_string()
-        }
-    };
-    save_config(file_path.as_path(), &name, &ip);
-    println!(">>> name: {}", name);
-    println!(">>> other ip: {:?}", ip);
-    (name, ip)
+fn local_validator(file_path: &Path) -> Option<(u64, u64)> {
+    let metadata = std::fs::metadata(file_path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok()?.as_secs();
+    Some((metadata.len(), modified))
 }
 
-pub fn parse_send_file(msg : &String) -> Option<String> {
-    let start_pattern = ":send \"";
-    if !msg.starts_with(start_pattern) {
-        //println!(">>> !msg.starts_with");
-        return None;
-    } 
-    if !msg.ends_with('\"') {
-        //println!(">>> !msg.ends_with");
-        return None;
-    } 
-    if !(msg.len() > 8) {
-        //println!(">>> !msg.len() > 8");
+fn read_etags() -> serde_json::Map<String, serde_json::Value> {
+    std::fs::read_to_string(etags_path()).ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+lazy_static! {
+    /// Loaded once and written back whole under the lock, concurrent downloads keep each other's entries.
+    static ref ETAGS: Mutex<serde_json::Map<String, serde_json::Value>> = Mutex::new(read_etags());
+}
+
+/// The server ETag of a downloaded file, only while the local file is still the one that was saved.
+pub fn stored_etag(file_path: &Path) -> Option<String> {
+    let (size, modified) = local_validator(file_path)?;
+    let etags = ETAGS.lock().expect("etags");
+    let entry = etags.get(&file_path.to_string_lossy().into_owned())?;
+    if entry["size"].as_u64() != Some(size) || entry["modified"].as_u64() != Some(modified) {
         return None;
     }
-    let mut result = msg.replace(start_pattern, "");
-    result.pop();
-    Some(result)
+    entry["etag"].as_str().map(|etag| etag.to_string())
 }
 
-pub fn parse_receive_file(msg : &String) -> Option<String> {
-    let start_pattern = ":receive \"";
-    if !msg.starts_with(start_pattern) {
-        //println!(">>> !msg.starts_with");
-        return None;
-    } 
-    if !msg.ends_with('\"') {
-        //println!(">>> !msg.ends_with");
-        return None;
-    } 
-    if !(msg.len() > 10) {
-        //println!(">>> !msg.len() > 8");
-        return None;
+pub fn remember_etag(file_path: &Path, etag: &str) {
+    let (size, modified) = match local_validator(file_path) {
+        Some(validator) => validator,
+        None => return,
+    };
+    let mut etags = ETAGS.lock().expect("etags");
+    etags.insert(file_path.to_string_lossy().into_owned(), json!({ "etag": etag, "size": size, "modified": modified }));
+    if let Err(e) = std::fs::write(etags_path(), serde_json::Value::Object(etags.clone()).to_string()) {
+        crate::print(&format!(">>> Cannot save {:?}: {}", etags_path(), e));
+    }
+}
+
+pub fn history_path() -> PathBuf {
+    let mut file_path = dirs::data_local_dir().unwrap();
+    file_path.push("Connection.history");
+    file_path
+}
+
+pub const DIR_ARCHIVE_EXT: &str = ".dir.tar";
+
+pub fn expand_paths(args : &[String]) -> Vec<PathBuf> {
+    let mut result = Vec::new();
+    for arg in args {
+        let is_pattern = arg.contains(['*', '?', '[']);
+        let matches : Vec<PathBuf> = match glob::glob(arg) {
+            Ok(paths) if is_pattern => paths.filter_map(Result::ok).collect(),
+            _ => Vec::new(),
+        };
+        if matches.is_empty() {
+            result.push(PathBuf::from(arg));
+        } else {
+            result.extend(matches);
+        }
     }
-    let mut result = msg.replace(start_pattern, "");
-    result.pop();
-    Some(result)
+    result
 }
@@ -0,0 +1,246 @@
+use std::string::String;
+use crate::{direct, print, style, transcript, transfers};
+
+pub const COMMAND_PREFIX: char = ':';
+
+/// Everything a command may touch, borrowed from the input job.
+pub struct Context<'a> {
+    pub name: &'a str,
+    pub file_server_uri: &'a String,
+    pub compose: &'a mut Option<Vec<String>>,
+    /// Set by `:quit`, the input job ends and the connection closes after it.
+    pub quit: bool,
+}
+
+/// Positional values and `--flags` of a command line.
+pub struct Args {
+    pub values: Vec<String>,
+    pub flags: Vec<String>,
+}
+
+impl Args {
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|flag| flag == name)
+    }
+}
+
+type RunFn = fn(&mut Context, Args, &str) -> Result<(), String>;
+
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    /// What tab completion inserts for the command.
+    pub completion: &'static str,
+    min_args: usize,
+    run: RunFn,
+}
+
+/// Splits on whitespace, `"` groups words and `""` inside quotes is a literal quote. Backslashes are
+/// plain characters so Windows paths, even with a trailing `\`, need no escaping. Unquoted words
+/// starting with `--` are flags until a lone `--`.
+pub fn parse_args(line: &str) -> Args {
+    let mut args = Args { values: Vec::new(), flags: Vec::new() };
+    let mut current = String::new();
+    let (mut in_quotes, mut quoted, mut flags_done) = (false, false, false);
+    let mut chars = line.chars().peekable();
+    let mut finish = |current: &mut String, quoted: &mut bool, args: &mut Args| {
+        let word = std::mem::take(current);
+        if !*quoted && !flags_done && word == "--" {
+            flags_done = true;
+        } else if !*quoted && !flags_done && word.starts_with("--") {
+            args.flags.push(word[2..].to_string());
+        } else if !word.is_empty() || *quoted {
+            args.values.push(word);
+        }
+        *quoted = false;
+    };
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                chars.next();
+                current.push('"');
+            },
+            '"' => {
+                in_quotes = !in_quotes;
+                quoted = true;
+            },
+            ch if ch.is_whitespace() && !in_quotes => {
+                if !current.is_empty() || quoted {
+                    finish(&mut current, &mut quoted, &mut args);
+                }
+            },
+            ch => current.push(ch),
+        }
+    }
+    if !current.is_empty() || quoted {
+        finish(&mut current, &mut quoted, &mut args);
+    }
+    args
+}
+
+fn parse_number(value: &str) -> Result<usize, String> {
+    value.parse().map_err(|_| format!("Not a number: {}", value))
+}
+
+/////////////////////////////////////////////////////////////////
+
+fn run_send(context: &mut Context, args: Args, _: &str) -> Result<(), String> {
+    crate::queue_send_files(&args.values, context.file_server_uri);
+    Ok(())
+}
+
+fn run_receive(context: &mut Context, args: Args, _: &str) -> Result<(), String> {
+    for filename in &args.values {
+        crate::queue_receive_file_request(filename, context.file_server_uri);
+    }
+    Ok(())
+}
+
+fn run_transfers(_: &mut Context, _: Args, _: &str) -> Result<(), String> {
+    let list = transfers::list();
+    if list.is_empty() { print(">>> No transfers"); }
+    for transfer in list { print(&format!(">>> {}", transfer)); }
+    Ok(())
+}
+
+fn run_cancel(_: &mut Context, args: Args, _: &str) -> Result<(), String> {
+    let id = parse_number(&args.values[0])?;
+    if !transfers::cancel(id) {
+        return Err(format!("No transfer [{}]", id));
+    }
+    Ok(())
+}
+
+fn run_offer(_: &mut Context, mut args: Args, _: &str) -> Result<(), String> {
+    let relay = args.flag("relay");
+    let path = args.values.remove(1);
+    direct::offer_file(args.values.remove(0), path.into(), relay);
+    Ok(())
+}
+
+fn run_relay(_: &mut Context, mut args: Args, _: &str) -> Result<(), String> {
+    let path = args.values.remove(1);
+    direct::offer_file(args.values.remove(0), path.into(), true);
+    Ok(())
+}
+
+fn run_accept(_: &mut Context, args: Args, _: &str) -> Result<(), String> {
+    direct::accept_offer(parse_number(&args.values[0])?);
+    Ok(())
+}
+
+fn run_msg(context: &mut Context, _: Args, rest: &str) -> Result<(), String> {
+    let rest = rest.trim();
+    let pos = rest.find(char::is_whitespace).ok_or_else(|| "Nothing to send".to_string())?;
+    let (nick, text) = (&rest[..pos], rest[pos..].trim());
+    direct::send_message(nick, text);
+    print(&style::format_direct(nick, text, true));
+    transcript::log(&transcript::dm_room(nick), Some(context.name), text);
+    Ok(())
+}
+
+fn run_log(_: &mut Context, args: Args, _: &str) -> Result<(), String> {
+    match args.values[0].as_str() {
+        "on" => transcript::set_enabled(true),
+        "off" => transcript::set_enabled(false),
+        other => return Err(format!("Expected on or off, not {}", other)),
+    }
+    Ok(())
+}
+
+fn run_compose(context: &mut Context, _: Args, _: &str) -> Result<(), String> {
+    crate::set_status(Some(format!(">>> Composing, '{}' sends, '{}' discards", crate::COMPOSE_END, crate::COMPOSE_ABORT)));
+    *context.compose = Some(Vec::new());
+    Ok(())
+}
+
+fn run_help(_: &mut Context, args: Args, _: &str) -> Result<(), String> {
+    if let Some(name) = args.values.first() {
+        let command = find(name.trim_start_matches(COMMAND_PREFIX)).ok_or_else(|| format!("Unknown command :{}", name))?;
+        print(&format!(">>> {}  {}", command.usage, command.help));
+        return Ok(());
+    }
+    for command in COMMANDS {
+        print(&format!(">>> {:<32} {}", command.usage, command.help));
+    }
+    print(">>> Quote arguments with spaces, \"\" in quotes is a literal quote, start a message with :: to send it as text");
+    Ok(())
+}
+
+fn run_quit(context: &mut Context, _: Args, _: &str) -> Result<(), String> {
+    context.quit = true;
+    Ok(())
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command { name: "send", usage: ":send \"<path>\"...", help: "upload files, directories or glob patterns to the server", completion: ":send \"", min_args: 1, run: run_send },
+    Command { name: "receive", usage: ":receive \"<file>\"...", help: "download files from the server", completion: ":receive \"", min_args: 1, run: run_receive },
+    Command { name: "transfers", usage: ":transfers", help: "list queued and active transfers", completion: ":transfers", min_args: 0, run: run_transfers },
+    Command { name: "cancel", usage: ":cancel <id>", help: "cancel a transfer", completion: ":cancel ", min_args: 1, run: run_cancel },
+    Command { name: "offer", usage: ":offer [--relay] <nick> \"<path>\"", help: "offer a file directly to a user", completion: ":offer ", min_args: 2, run: run_offer },
+    Command { name: "relay", usage: ":relay <nick> \"<path>\"", help: "offer a file to a user through the server relay", completion: ":relay ", min_args: 2, run: run_relay },
+    Command { name: "accept", usage: ":accept <number>", help: "accept a file offered to you", completion: ":accept ", min_args: 1, run: run_accept },
+    Command { name: "msg", usage: ":msg <nick> <text>", help: "send a direct message", completion: ":msg ", min_args: 2, run: run_msg },
+    Command { name: "log", usage: ":log on|off", help: "write transcripts of the chat", completion: ":log ", min_args: 1, run: run_log },
+    Command { name: "compose", usage: ":compose", help: "write a multi-line message", completion: ":compose", min_args: 0, run: run_compose },
+    Command { name: "help", usage: ":help [command]", help: "show this help", completion: ":help", min_args: 0, run: run_help },
+    Command { name: "quit", usage: ":quit", help: "leave the chat", completion: ":quit", min_args: 0, run: run_quit },
+];
+
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// Runs a `:command` line, errors are printed for the user.
+pub fn execute(context: &mut Context, line: &str) {
+    let line = &line[COMMAND_PREFIX.len_utf8()..];
+    let (name, rest) = match line.find(char::is_whitespace) {
+        Some(pos) => (&line[..pos], &line[pos..]),
+        None => (line, ""),
+    };
+    let command = match find(name) {
+        Some(command) => command,
+        None => {
+            print(&format!(">>> Unknown command :{}, see :help (start with :: to send it as text)", name));
+            return;
+        },
+    };
+    let args = parse_args(rest);
+    if args.values.len() < command.min_args {
+        print(&format!(">>> Usage: {}", command.usage));
+        return;
+    }
+    if let Err(e) = (command.run)(context, args, rest) {
+        print(&format!(">>> {}", e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_quoted_values_and_flags() {
+        let args = parse_args(r#"report.txt "my notes.txt" --force """#);
+        assert_eq!(args.values, vec!["report.txt", "my notes.txt", ""]);
+        assert_eq!(args.flags, vec!["force"]);
+    }
+
+    #[test]
+    fn doubled_quotes_are_literal() {
+        assert_eq!(parse_args(r#""say ""hi""""#).values, vec![r#"say "hi""#]);
+    }
+
+    #[test]
+    fn backslashes_are_literal() {
+        assert_eq!(parse_args(r#""C:\dir\" next"#).values, vec![r"C:\dir\", "next"]);
+    }
+
+    #[test]
+    fn double_dash_ends_flags() {
+        let args = parse_args("--all -- --name");
+        assert_eq!(args.flags, vec!["all"]);
+        assert_eq!(args.values, vec!["--name"]);
+    }
+}
@@ -0,0 +1,91 @@
+use std::path::{Path, MAIN_SEPARATOR};
+use std::sync::Mutex;
+use std::string::String;
+
+lazy_static! {
+    static ref MEMBERS: Mutex<Vec<(String, bool)>> = Mutex::new(Vec::new());
+    static ref REMOTE_FILES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+/// Takes `MEMBERS` and `FILES`/`FILES+` lists sent by the server, returns false for other control lines.
+pub fn handle_control(msg: &str) -> bool {
+    let fields = match connection_utils::parse_control(msg, usize::MAX) {
+        Some(fields) => fields,
+        None => return false,
+    };
+    let values = fields[1..].iter().filter(|field| !field.is_empty());
+    match fields[0] {
+        "MEMBERS" => update_members(values.map(|field| match field.strip_suffix(connection_utils::AWAY_SUFFIX) {
+            Some(name) => (name.to_string(), true),
+            None => (field.to_string(), false),
+        }).collect()),
+        "FILES" => *REMOTE_FILES.lock().expect("remote files") = values.map(|name| connection_utils::percent_decode(name)).collect(),
+        "FILES+" => REMOTE_FILES.lock().expect("remote files").extend(values.map(|name| connection_utils::percent_decode(name))),
+        _ => return false,
+    }
+    true
+}
+
+/// Keeps the names for completion and reports the users who went away or came back since the last list.
+fn update_members(members: Vec<(String, bool)>) {
+    let mut known = MEMBERS.lock().expect("members");
+    for (name, away) in &members {
+        let was_away = known.iter().find(|(known_name, _)| known_name == name).map(|(_, was_away)| *was_away);
+        match (was_away, *away) {
+            (Some(false), true) => crate::print(&format!(">>> {} is away", name)),
+            (Some(true), false) => crate::print(&format!(">>> {} is back", name)),
+            _ => {},
+        }
+    }
+    *known = members;
+}
+
+fn matching(candidates: &[String], prefix: &str, suffix: &str) -> Vec<String> {
+    candidates.iter()
+        .filter(|candidate| candidate.starts_with(prefix))
+        .map(|candidate| format!("{}{}", candidate, suffix))
+        .collect()
+}
+
+fn complete_local_path(prefix: &str) -> Vec<String> {
+    let split = prefix.rfind(['/', MAIN_SEPARATOR]).map(|i| i + 1).unwrap_or(0);
+    let (dir, file_prefix) = prefix.split_at(split);
+    let entries = match std::fs::read_dir(if dir.is_empty() { Path::new(".") } else { Path::new(dir) }) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut result: Vec<String> = entries.filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.starts_with(file_prefix) {
+                return None;
+            }
+            let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+            Some(format!("{}{}{}", dir, name, if is_dir { MAIN_SEPARATOR } else { '"' }))
+        })
+        .collect();
+    result.sort();
+    result
+}
+
+/// Completes `:` commands, local paths after `:send "`, remote files after `:receive "` and nicknames.
+pub fn complete(line: &str) -> (usize, Vec<String>) {
+    let open_quote = line.rfind('"').filter(|_| line.matches('"').count() % 2 == 1);
+    if let Some(quote) = open_quote {
+        let argument = &line[quote + 1..];
+        if line.starts_with(":send ") {
+            return (quote + 1, complete_local_path(argument));
+        }
+        if line.starts_with(":receive ") {
+            return (quote + 1, matching(&REMOTE_FILES.lock().expect("remote files"), argument, "\""));
+        }
+        return (quote + 1, Vec::new());
+    }
+    if line.starts_with(':') && !line.contains(' ') {
+        let commands: Vec<String> = crate::commands::COMMANDS.iter().map(|command| command.completion.to_string()).collect();
+        return (0, matching(&commands, line, ""));
+    }
+    let start = line.rfind(' ').map(|i| i + 1).unwrap_or(0);
+    let names: Vec<String> = MEMBERS.lock().expect("members").iter().map(|(name, _)| name.clone()).collect();
+    (start, matching(&names, &line[start..], " "))
+}
@@ -0,0 +1,210 @@
+extern crate toml;
+
+use std::collections::BTreeMap;
+use std::net::Ipv6Addr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::string::String;
+use crate::print;
+
+pub const CONFIG_FILE: &str = "Connection.toml";
+pub const LEGACY_CONFIG_FILE: &str = "Connection.cfg";
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Profile {
+    pub host: String,
+    pub text_port: u16,
+    pub file_port: u16,
+    pub nick: String,
+    /// Not supported yet, kept so a profile that asks for TLS is refused instead of connecting in plain text.
+    #[serde(skip_serializing)]
+    pub tls: bool,
+    pub download_dir: Option<PathBuf>,
+    pub colors: bool,
+    pub highlights: Vec<String>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            host: "89.67.243.241".to_string(),
+            text_port: connection_utils::SERVER_PORT_TEXT,
+            file_port: connection_utils::SERVER_PORT_FILE,
+            nick: "UnnamedUser".to_string(),
+            tls: false,
+            download_dir: None,
+            colors: true,
+            highlights: Vec::new(),
+        }
+    }
+}
+
+impl Profile {
+    pub fn download_dir(&self) -> PathBuf {
+        self.download_dir.clone().unwrap_or_else(|| dirs::download_dir().unwrap())
+    }
+
+    /// IPv6 literals go in brackets in a URI.
+    pub fn file_server_uri(&self) -> String {
+        if self.host.parse::<Ipv6Addr>().is_ok() {
+            return format!("http://[{}]:{}/", &self.host, self.file_port);
+        }
+        format!("http://{}:{}/", &self.host, self.file_port)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct ClientConfig {
+    pub default_profile: Option<String>,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// `[--profile <name>] [nick] [host]`, the positional values override the profile for this run only.
+#[derive(Default)]
+struct CommandLine {
+    profile: Option<String>,
+    nick: Option<String>,
+    host: Option<String>,
+}
+
+fn command_line() -> Result<CommandLine, String> {
+    let mut result = CommandLine::default();
+    let mut positional = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--profile" {
+            result.profile = Some(args.next().ok_or_else(|| "--profile needs a name".to_string())?);
+        } else {
+            positional.push(arg);
+        }
+    }
+    let mut positional = positional.into_iter();
+    result.nick = positional.next();
+    result.host = positional.next();
+    Ok(result)
+}
+
+pub fn config_path() -> PathBuf {
+    let mut file_path = dirs::data_local_dir().unwrap();
+    file_path.push(CONFIG_FILE);
+    file_path
+}
+
+/// Builds the first `Connection.toml` from the old positional `Connection.cfg`, the only time the file is written.
+fn create(file_path: &Path) -> ClientConfig {
+    let mut legacy_path = dirs::data_local_dir().unwrap();
+    legacy_path.push(LEGACY_CONFIG_FILE);
+    let (nick, host, highlights) = crate::clientonly::read_config(&legacy_path);
+    let mut profile = Profile::default();
+    if let Some(nick) = nick { profile.nick = nick; }
+    if let Some(host) = host { profile.host = host; }
+    profile.highlights = highlights;
+
+    let mut profiles = BTreeMap::new();
+    profiles.insert(DEFAULT_PROFILE.to_string(), profile);
+    let config = ClientConfig { default_profile: Some(DEFAULT_PROFILE.to_string()), profiles };
+    match toml::to_string(&config).map_err(|e| e.to_string())
+        .and_then(|content| std::fs::write(file_path, content).map_err(|e| e.to_string())) {
+        Ok(()) => print(&format!(">>> Created config {:?}", file_path)),
+        Err(e) => print(&format!(">>> Could not create config {:?}: {}", file_path, e)),
+    }
+    config
+}
+
+fn read(file_path: &Path) -> Result<ClientConfig, String> {
+    if !file_path.is_file() {
+        return Ok(create(file_path));
+    }
+    let content = std::fs::read_to_string(file_path).map_err(|e| e.to_string())?;
+    toml::from_str(&content).map_err(|e| e.to_string())
+}
+
+fn select(mut config: ClientConfig, cli: CommandLine) -> Result<(String, Profile), String> {
+    let name = match cli.profile.or(config.default_profile) {
+        Some(name) => name,
+        None if config.profiles.len() == 1 => config.profiles.keys().next().expect("one profile").clone(),
+        None => DEFAULT_PROFILE.to_string(),
+    };
+    let mut profile = match config.profiles.remove(&name) {
+        Some(profile) => profile,
+        None if config.profiles.is_empty() && name == DEFAULT_PROFILE => Profile::default(),
+        None => {
+            let known: Vec<String> = config.profiles.keys().cloned().collect();
+            return Err(format!("No profile '{}', known profiles: {}", name, known.join(", ")));
+        }
+    };
+    if profile.tls {
+        return Err(format!("Profile '{}' sets tls, TLS is not supported yet", name));
+    }
+    if let Some(nick) = cli.nick { profile.nick = nick; }
+    if let Some(host) = cli.host { profile.host = host; }
+    Ok((name, profile))
+}
+
+/// Reads the config and picks the profile for this run, errors stop the client rather than connecting somewhere unexpected.
+pub fn load() -> Result<(String, Profile), String> {
+    let cli = command_line()?;
+    let file_path = config_path();
+    let config = read(&file_path).map_err(|e| format!("Wrong config {:?}: {}", &file_path, e))?;
+    select(config, cli)
+}
+
+lazy_static! {
+    static ref DOWNLOAD_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+pub fn init(profile: &Profile) {
+    *DOWNLOAD_DIR.lock().expect("download dir") = Some(profile.download_dir());
+}
+
+pub fn download_dir() -> PathBuf {
+    DOWNLOAD_DIR.lock().expect("download dir").clone().unwrap_or_else(|| dirs::download_dir().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(text: &str) -> ClientConfig {
+        toml::from_str(text).expect("test config")
+    }
+
+    #[test]
+    fn select_uses_the_default_profile() {
+        let cfg = config("default_profile = \"work\"\n[profiles.home]\nnick = \"Home\"\n[profiles.work]\nnick = \"Work\"\n");
+        let (name, profile) = select(cfg, CommandLine::default()).unwrap();
+        assert_eq!((name.as_str(), profile.nick.as_str()), ("work", "Work"));
+    }
+
+    #[test]
+    fn select_takes_a_single_profile() {
+        let (name, profile) = select(config("[profiles.home]\nhost = \"::1\"\n"), CommandLine::default()).unwrap();
+        assert_eq!(name, "home");
+        assert_eq!(profile.file_server_uri(), format!("http://[::1]:{}/", connection_utils::SERVER_PORT_FILE));
+    }
+
+    #[test]
+    fn select_rejects_unknown_profiles() {
+        let cli = CommandLine { profile: Some("office".to_string()), ..CommandLine::default() };
+        assert!(select(config("[profiles.home]\n"), cli).is_err());
+    }
+
+    #[test]
+    fn select_refuses_tls_profiles() {
+        let err = select(config("[profiles.home]\ntls = true\n"), CommandLine::default()).err().unwrap();
+        assert!(err.contains("TLS is not supported"));
+        assert!(!toml::to_string(&Profile::default()).unwrap().contains("tls"));
+    }
+
+    #[test]
+    fn command_line_overrides_the_profile() {
+        let cli = CommandLine { nick: Some("Cli".to_string()), host: Some("example.org".to_string()), ..CommandLine::default() };
+        let (name, profile) = select(ClientConfig::default(), cli).unwrap();
+        assert_eq!(name, DEFAULT_PROFILE);
+        assert_eq!((profile.nick.as_str(), profile.host.as_str()), ("Cli", "example.org"));
+        assert_eq!(profile.file_server_uri(), format!("http://example.org:{}/", connection_utils::SERVER_PORT_FILE));
+    }
+}
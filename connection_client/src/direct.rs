@@ -0,0 +1,326 @@
+extern crate tokio;
+extern crate futures;
+extern crate bytes;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::codec::{BytesCodec, FramedRead, FramedWrite};
+use tokio::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::string::String;
+use crate::transfers::{self, Direction};
+
+pub const OFFER_TIMEOUT: Duration = Duration::from_secs(300);
+
+pub const RELAY_ADDR: &str = "relay";
+pub const DIRECT_MESSAGE: &str = "MSG ";
+
+struct OutgoingOffer {
+    to: String,
+    path: PathBuf,
+    relay: bool,
+}
+
+#[derive(Clone)]
+struct IncomingOffer {
+    from: String,
+    token: String,
+    size: u64,
+    addr: Option<SocketAddr>,
+    filename: String,
+}
+
+struct DirectState {
+    text_sender: Option<connection_utils::Sender>,
+    file_server_uri: String,
+    local_ip: IpAddr,
+    outgoing: HashMap<String, OutgoingOffer>,
+    incoming: BTreeMap<usize, IncomingOffer>,
+    next_incoming: usize,
+    /// Accepted offers that fell back to the file server, by token, waiting for `UPLOADED`.
+    fallbacks: HashMap<String, IncomingOffer>,
+}
+
+lazy_static! {
+    static ref DIRECT: Mutex<DirectState> = Mutex::new(DirectState {
+        text_sender: None,
+        file_server_uri: String::new(),
+        local_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        outgoing: HashMap::new(),
+        incoming: BTreeMap::new(),
+        next_incoming: 1,
+        fallbacks: HashMap::new(),
+    });
+}
+
+/////////////////////////////////////////////////////////////////
+
+pub fn init(text_sender: connection_utils::Sender, file_server_uri: String) {
+    let mut state = DIRECT.lock().expect("direct");
+    state.text_sender = Some(text_sender);
+    state.file_server_uri = file_server_uri;
+}
+
+/// Drops the chat sender so the connection can close on `:quit`.
+pub fn shutdown() {
+    DIRECT.lock().expect("direct").text_sender = None;
+}
+
+pub fn set_local_ip(ip: IpAddr) {
+    DIRECT.lock().expect("direct").local_ip = ip;
+}
+
+fn new_token() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0));
+    format!("{:016x}", hasher.finish())
+}
+
+fn send_to(nick: &str, fields: &[&str]) {
+    let line = connection_utils::control_line(&["TO", nick, &fields.join(" ")]);
+    if let Some(sender) = DIRECT.lock().expect("direct").text_sender.as_mut() {
+        if let Err(e) = connection_utils::pass_line(sender, line) {
+            crate::print(&format!("Cannot send, error: {}", e));
+        }
+    }
+}
+
+pub fn send_message(nick: &str, text: &str) {
+    send_to(nick, &[DIRECT_MESSAGE.trim_end(), &connection_utils::escape_line(text)]);
+}
+
+fn relay_uri(file_server_uri: &str, token: &str) -> String {
+    format!("{}{}/{}", file_server_uri, RELAY_ADDR, token)
+}
+
+/// Only the last component of a name sent by a peer, so an offer cannot write outside the download directory.
+fn safe_filename(name: &str) -> Option<String> {
+    Path::new(name).file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .filter(|name| !name.is_empty() && name != "." && name != "..")
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/////////////////////////////////////////////////////////////////
+
+fn serve_offer(token: String, socket: TcpStream) {
+    let task = tokio::io::read_until(std::io::BufReader::new(socket), b'\n', Vec::new())
+        .and_then(move |(reader, line)| {
+            if String::from_utf8_lossy(&line).trim_end() != token {
+                return Err(invalid_data("wrong offer token"));
+            }
+            Ok((reader.into_inner(), token))
+        })
+        .map(|(socket, token)| {
+            let offer = match DIRECT.lock().expect("direct").outgoing.remove(&token) {
+                Some(offer) => offer,
+                None => return,
+            };
+            let filename = offer.path.file_name().expect("proper filename").to_string_lossy().into_owned();
+            crate::print(&format!(">>> {} accepted {}", offer.to, filename));
+            transfers::enqueue(filename, Direction::Upload, Box::new(move |id| {
+                Box::new(tokio::fs::File::open(offer.path)
+                    .and_then(|file| file.metadata())
+                    .and_then(move |(file, metadata)| {
+                        transfers::set_total(id, metadata.len());
+                        FramedRead::new(file, BytesCodec::new())
+                            .map(move |chunk| {
+                                transfers::add_progress(id, chunk.len() as u64);
+                                chunk.freeze()
+                            })
+                            .forward(FramedWrite::new(socket, BytesCodec::new()))
+                    })
+                    .map(|_| crate::print(">>> Direct transfer finished"))
+                    .map_err(|err| crate::print(&format!(">>> Direct transfer error {:?}", err))))
+            }));
+        })
+        .map_err(|err| crate::print(&format!(">>> Direct connection error {:?}", err)));
+    tokio::spawn(task);
+}
+
+/// Accepts connections until one of them brings the token or the offer expires, so a stray
+/// connection does not push the receiver to the relay.
+fn listen_for_offer(token: String, local_ip: IpAddr) -> Option<SocketAddr> {
+    let listener = match TcpListener::bind(&SocketAddr::new(local_ip, 0)) {
+        Ok(listener) => listener,
+        Err(e) => { crate::print(&format!(">>> Cannot listen for direct transfer {:?}", e)); return None; }
+    };
+    let listen_addr = listener.local_addr().expect("listener address");
+    let (pending, served) = (token.clone(), token.clone());
+    let task = listener.incoming()
+        .take_while(move |_| Ok(DIRECT.lock().expect("direct").outgoing.contains_key(&pending)))
+        .for_each(move |socket| { serve_offer(served.clone(), socket); Ok(()) })
+        .timeout(OFFER_TIMEOUT)
+        .then(move |_| {
+            if DIRECT.lock().expect("direct").outgoing.remove(&token).is_some() {
+                crate::print(&format!(">>> Offer of {} expired", token));
+            }
+            Ok(())
+        });
+    tokio::spawn(task);
+    Some(listen_addr)
+}
+
+pub fn offer_file(nick: String, path: PathBuf, relay: bool) {
+    if !path.is_file() { crate::print(&format!(">>> WRONG PATH {}", path.display())); return; }
+    let filename = path.file_name().expect("proper filename").to_string_lossy().into_owned();
+    let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+    let local_ip = DIRECT.lock().expect("direct").local_ip;
+    if local_ip.is_unspecified() { crate::print(">>> Not connected"); return; }
+    let token = new_token();
+    let addr = if relay {
+        RELAY_ADDR.to_string()
+    } else {
+        match listen_for_offer(token.clone(), local_ip) {
+            Some(listen_addr) => listen_addr.to_string(),
+            None => return,
+        }
+    };
+    DIRECT.lock().expect("direct").outgoing.insert(token.clone(), OutgoingOffer { to: nick.clone(), path, relay });
+    send_to(&nick, &["OFFER", &token, &size.to_string(), &addr, &filename]);
+    crate::print(&format!(">>> Offered {} to {}", filename, nick));
+}
+
+pub fn accept_offer(number: usize) {
+    let offer = match DIRECT.lock().expect("direct").incoming.remove(&number) {
+        Some(offer) => offer,
+        None => { crate::print(&format!(">>> No offer [{}]", number)); return; }
+    };
+    let mut file_path = crate::config::download_dir();
+    file_path.push(&offer.filename);
+    let addr = match offer.addr {
+        Some(addr) => addr,
+        None => {
+            let file_server_uri = DIRECT.lock().expect("direct").file_server_uri.clone();
+            let uri = relay_uri(&file_server_uri, &offer.token).parse().expect("valid uri");
+            let (from, token) = (offer.from, offer.token);
+            crate::queue_download(offer.filename, uri, file_path, Some(offer.size), Some(Box::new(move || {
+                send_to(&from, &["ACCEPT", &token]);
+            })));
+            return;
+        }
+    };
+    transfers::enqueue(offer.filename.clone(), Direction::Download, Box::new(move |id| {
+        transfers::set_total(id, offer.size);
+        let fallback = IncomingOffer { addr: None, ..offer.clone() };
+        Box::new(TcpStream::connect(&addr)
+            .map_err(move |err| {
+                crate::print(&format!(">>> Direct connection failed ({:?}), relaying through server", err));
+                let (from, token) = (fallback.from.clone(), fallback.token.clone());
+                DIRECT.lock().expect("direct").fallbacks.insert(token.clone(), fallback);
+                send_to(&from, &["FALLBACK", &token]);
+            })
+            .and_then(move |socket| {
+                tokio::io::write_all(socket, format!("{}\n", offer.token))
+                    .and_then(move |(socket, _)| {
+                        FramedRead::new(socket, BytesCodec::new())
+                            .fold(Vec::new(), move |mut content, chunk| {
+                                transfers::add_progress(id, chunk.len() as u64);
+                                content.extend(&chunk[..]);
+                                future::ok::<_, std::io::Error>(content)
+                            })
+                    })
+                    .and_then(move |content| {
+                        if content.len() as u64 != offer.size {
+                            return Err(invalid_data("transfer incomplete"));
+                        }
+                        crate::write_downloaded_file(&file_path, &content)
+                    })
+                    .map_err(|err| crate::print(&format!(">>> Direct transfer error {:?}", err)))
+            }))
+    }));
+}
+
+/////////////////////////////////////////////////////////////////
+
+fn handle_offer(from: &str, fields: &[&str]) {
+    let addr = if fields[2] == RELAY_ADDR { Ok(None) } else { fields[2].parse().map(Some) };
+    let (size, addr) = match (fields[1].parse(), addr) {
+        (Ok(size), Ok(addr)) => (size, addr),
+        _ => return,
+    };
+    let filename = match safe_filename(fields[3]) {
+        Some(filename) => filename,
+        None => { crate::print(&format!(">>> Ignored offer from {} with a wrong file name", from)); return; }
+    };
+    let mut state = DIRECT.lock().expect("direct");
+    let number = state.next_incoming;
+    state.next_incoming += 1;
+    let mode = if addr.is_some() { "direct" } else { "relay" };
+    crate::print(&format!(">>> {} offers {} ({}, {}), type :accept {}", from, &filename, transfers::format_bytes(size), mode, number));
+    state.incoming.insert(number, IncomingOffer { from: from.to_string(), token: fields[0].to_string(), size, addr, filename });
+}
+
+fn take_offer(from: &str, token: &str, relay: bool) -> Option<(OutgoingOffer, String)> {
+    let mut state = DIRECT.lock().expect("direct");
+    match state.outgoing.get(token) {
+        Some(offer) if offer.to == from && offer.relay == relay => {},
+        _ => return None,
+    }
+    let offer = state.outgoing.remove(token).expect("known offer");
+    Some((offer, state.file_server_uri.clone()))
+}
+
+fn handle_relay_accept(from: &str, token: &str) {
+    if let Some((offer, file_server_uri)) = take_offer(from, token, true) {
+        let filename = offer.path.file_name().expect("proper filename").to_string_lossy().into_owned();
+        crate::print(&format!(">>> {} accepted {}", from, filename));
+        crate::queue_upload(offer.path, filename, relay_uri(&file_server_uri, token), None);
+    }
+}
+
+fn handle_fallback(from: &str, token: &str) {
+    let (offer, file_server_uri) = match take_offer(from, token, false) {
+        Some(offer) => offer,
+        None => return,
+    };
+    let filename = offer.path.file_name().expect("proper filename").to_string_lossy().into_owned();
+    let (nick, token) = (from.to_string(), token.to_string());
+    crate::queue_send_single_file(offer.path, file_server_uri, Some(Box::new(move || {
+        send_to(&nick, &["UPLOADED", &token, &filename]);
+    })));
+}
+
+/// Downloads a fallback upload, only for a token this client asked for by sending `FALLBACK` to the same peer.
+fn handle_uploaded(from: &str, token: &str) {
+    let mut state = DIRECT.lock().expect("direct");
+    match state.fallbacks.get(token) {
+        Some(offer) if offer.from == from => {},
+        _ => return,
+    }
+    let offer = state.fallbacks.remove(token).expect("known fallback");
+    let file_server_uri = state.file_server_uri.clone();
+    drop(state);
+    crate::queue_receive_file_request(&offer.filename, &file_server_uri);
+}
+
+pub fn handle_control(msg: &str) {
+    let fields = match connection_utils::parse_control(msg, 3) {
+        Some(fields) => fields,
+        None => return,
+    };
+    if fields.len() != 3 || fields[0] != "FROM" {
+        return;
+    }
+    let from = fields[1];
+    if fields[2].starts_with(DIRECT_MESSAGE) {
+        crate::show_direct_message(from, &connection_utils::unescape_line(&fields[2][DIRECT_MESSAGE.len()..]));
+        return;
+    }
+    let payload: Vec<&str> = fields[2].splitn(5, ' ').collect();
+    match payload[0] {
+        "OFFER" if payload.len() == 5 => handle_offer(from, &payload[1..]),
+        "FALLBACK" if payload.len() == 2 => handle_fallback(from, payload[1]),
+        "ACCEPT" if payload.len() == 2 => handle_relay_accept(from, payload[1]),
+        "UPLOADED" if payload.len() >= 3 => handle_uploaded(from, payload[1]),
+        _ => {},
+    }
+}
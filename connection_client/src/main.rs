@@ -2,75 +2,193 @@ extern crate tokio;
 extern crate futures;
 extern crate bytes;
 extern crate console;
+extern crate ansi_escapes;
 extern crate connection_utils;
 extern crate hyper;
 extern crate dirs;
+extern crate tar;
+extern crate glob;
+#[macro_use]
+extern crate serde_json;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
 #[macro_use]
 extern crate lazy_static;
 
 mod clientonly;
+mod transfers;
+mod direct;
+mod completion;
+mod style;
+mod transcript;
+mod commands;
+mod config;
 
 use tokio::net::TcpStream;
 use tokio::prelude::*;
 use tokio::runtime::Builder;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::string::String;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::str::FromStr;
+use std::net::{SocketAddr, ToSocketAddrs};
+use transfers::{Direction, TransferFuture, TransferId};
 
 lazy_static! { 
     static ref CONSOLE: connection_utils::SafeConsole = Arc::new(Mutex::new(connection_utils::ConsoleBuf::new())); 
 }
 
 pub fn print(line : &str) { CONSOLE.lock().unwrap().cprint(line); }
+pub fn set_status(status : Option<String>) { CONSOLE.lock().unwrap().set_status(status); }
+
+struct StatusBar {
+    nick: String,
+    server: String,
+    state: &'static str,
+}
+
+lazy_static! {
+    static ref STATUS_BAR: Mutex<StatusBar> = Mutex::new(StatusBar { nick: String::new(), server: String::new(), state: "offline" });
+}
+
+fn update_status_bar<F: FnOnce(&mut StatusBar)>(update: F) {
+    let mut bar = STATUS_BAR.lock().expect("status bar");
+    update(&mut bar);
+    CONSOLE.lock().unwrap().set_status_bar(format!("{} @ {} | {}", bar.nick, bar.server, bar.state));
+}
 
 /////////////////////////////////////////////////////////////////
 
-fn spawn_put_request(content : Vec<u8>, uri_str : String ) {
-    let uri : hyper::Uri = uri_str.parse().expect("valid uri");
-    let request = hyper::Request::put(uri).body(content.into()).expect("request builder");
-    let future = hyper::Client::new().request(request)
-        .and_then(move |res| { print(&format!(">>> Response: {}", res.status())); Ok(()) })
-        .map_err( move |err| { print(&format!(">>> Receive response error {:?}", err)); });
-    tokio::spawn(future);
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+fn progress_body<S>(id: TransferId, chunks: S) -> hyper::Body
+    where S: Stream<Item = bytes::Bytes, Error = std::io::Error> + Send + 'static {
+    hyper::Body::wrap_stream(chunks.map(move |chunk| {
+        transfers::add_progress(id, chunk.len() as u64);
+        hyper::Chunk::from(chunk)
+    }))
 }
 
-fn spawn_send_single_file(path_str: String, file_server_uri: String) {
-    let path = std::path::Path::new(&path_str);
-    if !path.exists() || !path.is_file() { print(&format!(">>> WRONG PATH {}", &path_str)); return; }
-    let filename = path.file_name().expect("proper filename").to_str().expect("string").to_string();
-    let task = tokio::fs::File::open(path_str.clone())
-        .and_then(move |mut file| {
-            let mut content : Vec<u8> = vec![];
-            if let Err(e) = file.read_to_end(&mut content) { return Err(e); }
-            print(&format!(">>> Sending... {} {} bytes", &filename, content.len()));
-            spawn_put_request(content, format!("{}{}", file_server_uri, filename));
+pub const COMPOSE_END: &str = ".";
+pub const COMPOSE_ABORT: &str = ":abort";
+pub type NotifyFn = Box<dyn FnOnce() + Send>;
+
+fn put_request(body: hyper::Body, uri_str: String, uploaded: Option<NotifyFn>) -> TransferFuture {
+    let uri : hyper::Uri = uri_str.parse().expect("valid uri");
+    let request = hyper::Request::put(uri).body(body).expect("request builder");
+    Box::new(hyper::Client::new().request(request)
+        .and_then(move |res| {
+            print(&format!(">>> Response: {}", res.status()));
+            if let (true, Some(uploaded)) = (res.status().is_success(), uploaded) { uploaded(); }
             Ok(())
         })
-        .map_err(|err| { print(&format!(">>> Send file error {:?}", err)); });
-    tokio::spawn(task);
+        .map_err( move |err| { print(&format!(">>> Receive response error {:?}", err)); }))
+}
+
+pub fn queue_upload(path: std::path::PathBuf, filename: String, uri_str: String, uploaded: Option<NotifyFn>) {
+    transfers::enqueue(filename.clone(), Direction::Upload, Box::new(move |id| {
+        Box::new(tokio::fs::File::open(path)
+            .and_then(|file| file.metadata())
+            .map_err(|err| { print(&format!(">>> Send file error {:?}", err)); })
+            .and_then(move |(file, metadata)| {
+                transfers::set_total(id, metadata.len());
+                print(&format!(">>> Sending... {} {} bytes", &filename, metadata.len()));
+                let chunks = tokio::codec::FramedRead::new(file, tokio::codec::BytesCodec::new()).map(|chunk| chunk.freeze());
+                put_request(progress_body(id, chunks), uri_str, uploaded)
+            }))
+    }));
+}
+
+pub fn queue_send_single_file(path: std::path::PathBuf, file_server_uri: String, uploaded: Option<NotifyFn>) {
+    if !path.exists() || !path.is_file() { print(&format!(">>> WRONG PATH {}", path.display())); return; }
+    let filename = path.file_name().expect("proper filename").to_str().expect("string").to_string();
+    let uri_str = format!("{}{}", file_server_uri, connection_utils::percent_encode(&filename));
+    queue_upload(path, filename, uri_str, uploaded);
+}
+
+fn queue_send_directory(path: std::path::PathBuf, file_server_uri: String) {
+    let dirname = match path.canonicalize().map(|p| p.file_name().map(|n| n.to_string_lossy().into_owned())) {
+        Ok(Some(name)) => name,
+        _ => { print(&format!(">>> WRONG PATH {}", path.display())); return; }
+    };
+    let filename = format!("{}{}", dirname, clientonly::DIR_ARCHIVE_EXT);
+    transfers::enqueue(filename.clone(), Direction::Upload, Box::new(move |id| {
+        Box::new(future::lazy(move || {
+                let mut archive = tar::Builder::new(Vec::new());
+                archive.append_dir_all(&dirname, &path)?;
+                archive.into_inner()
+            })
+            .map_err(|err : std::io::Error| { print(&format!(">>> Send directory error {:?}", err)); })
+            .and_then(move |content| {
+                transfers::set_total(id, content.len() as u64);
+                print(&format!(">>> Sending directory... {} {} bytes", &filename, content.len()));
+                let mut content = bytes::Bytes::from(content);
+                let mut chunks = Vec::new();
+                while !content.is_empty() {
+                    let size = std::cmp::min(UPLOAD_CHUNK_SIZE, content.len());
+                    chunks.push(content.split_to(size));
+                }
+                put_request(progress_body(id, stream::iter_ok(chunks)), format!("{}{}", file_server_uri, connection_utils::percent_encode(&filename)), None)
+            }))
+    }));
+}
+
+fn queue_send_files(args: &[String], file_server_uri: &str) {
+    for path in clientonly::expand_paths(args) {
+        if path.is_dir() {
+            queue_send_directory(path, file_server_uri.to_string());
+        } else {
+            queue_send_single_file(path, file_server_uri.to_string(), None);
+        }
+    }
 }
 
 /////////////////////////////////////////////////////////////////
 
-fn spawn_save_body_to_file(body: hyper::Body, file_path: std::path::PathBuf) {
-    let task = body
-        .fold(Vec::new(), |mut v, chunk| {
+pub fn write_downloaded_file(file_path: &std::path::Path, content: &[u8]) -> std::io::Result<()> {
+    let mut file = std::fs::File::create(file_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    print(&format!(">>> Saved {}", file_path.to_str().unwrap()));
+    if file_path.to_string_lossy().ends_with(clientonly::DIR_ARCHIVE_EXT) {
+        let mut archive = tar::Archive::new(content);
+        archive.unpack(file_path.parent().expect("download directory"))?;
+        print(&format!(">>> Unpacked directory from {}", file_path.to_str().unwrap()));
+    }
+    Ok(())
+}
+
+/// Nothing is written unless the whole body arrived, `expected` bytes of it when the size is known.
+fn save_body_to_file(id: TransferId, body: hyper::Body, file_path: std::path::PathBuf, expected: Option<u64>, etag: Option<String>) -> TransferFuture {
+    Box::new(body
+        .fold(Vec::new(), move |mut v, chunk| {
+            transfers::add_progress(id, chunk.len() as u64);
             v.extend(&chunk[..]);
             future::ok::<_, hyper::Error>(v)
         })
         .map_err(|err| { std::io::Error::new(std::io::ErrorKind::Other, err.to_string()) })
         .and_then(move |chunks| {
-            let mut file = std::fs::File::create(&file_path)?;
-            if let Err(e) = file.write_all(&chunks) { return Err(e); }
-            if let Err(e) = file.sync_all()  { return Err(e); }
-            print(&format!(">>> Saved {}", file_path.to_str().unwrap()));
+            match expected {
+                Some(size) if size != chunks.len() as u64 => Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof,
+                    format!("got {} of {} bytes", chunks.len(), size))),
+                _ => write_downloaded_file(&file_path, &chunks),
+            }?;
+            if let Some(etag) = etag {
+                clientonly::remember_etag(&file_path, &etag);
+            }
             Ok(())
-        }).map_err(|err| { print(&format!(">>> Save file error: {:?}", err)); });
-    tokio::spawn(task);
+        })
+        .map_err(|err| { print(&format!(">>> Save file error: {:?}", err)); }))
 }
 
-fn spawn_receive_file_request(in_filename_str: &String, file_server_uri: &String) {
+fn content_length(response: &hyper::Response<hyper::Body>) -> Option<u64> {
+    response.headers().get(hyper::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+pub fn queue_receive_file_request(in_filename_str: &String, file_server_uri: &String) {
     let filename_buff = std::path::Path::new(in_filename_str);
     let mut filename = String::new();
     if let Some(_) = filename_buff.file_stem() {
@@ -83,61 +201,249 @@ fn spawn_receive_file_request(in_filename_str: &String, file_server_uri: &String
         return;
     }
 
-    let mut download_file_path = dirs::download_dir().unwrap();
+    let mut download_file_path = config::download_dir();
     download_file_path.push(&filename);
-    let uri : hyper::Uri = format!("{}{}", file_server_uri, filename).parse().expect("valid uri");
-    let request = hyper::Request::get(uri).body(hyper::Body::empty()).expect("request builder");
-    let future = hyper::Client::new().request(request)
-        .and_then(move |res| { 
-            if res.status() != hyper::http::StatusCode::OK {
-                print(&format!(">>> Response: {}", res.status())); 
-            } else {
-                 spawn_save_body_to_file(res.into_body(), download_file_path);
-            }
-            Ok(()) 
-        }).map_err( move |err| { print(&format!(">>> Receive response error {:?}", err)); });
-    tokio::spawn(future);
+    let uri : hyper::Uri = format!("{}{}", file_server_uri, connection_utils::percent_encode(&filename)).parse().expect("valid uri");
+    queue_download(filename, uri, download_file_path, None, None);
+}
+
+pub fn queue_download(filename: String, uri: hyper::Uri, file_path: std::path::PathBuf, size: Option<u64>, responded: Option<NotifyFn>) {
+    transfers::enqueue(filename.clone(), Direction::Download, Box::new(move |id| {
+        let mut request = hyper::Request::get(uri);
+        if let Some(etag) = clientonly::stored_etag(&file_path) {
+            request.header(hyper::header::IF_NONE_MATCH, etag.as_str());
+        }
+        let request = request.body(hyper::Body::empty()).expect("request builder");
+        Box::new(hyper::Client::new().request(request)
+            .map_err( move |err| { print(&format!(">>> Receive response error {:?}", err)); })
+            .and_then(move |res| -> TransferFuture {
+                if res.status() == hyper::http::StatusCode::NOT_MODIFIED {
+                    print(&format!(">>> {} is up to date", filename));
+                    return Box::new(future::ok(()));
+                }
+                if res.status() != hyper::http::StatusCode::OK {
+                    print(&format!(">>> Response: {}", res.status()));
+                    return Box::new(future::ok(()));
+                }
+                let expected = size.or_else(|| content_length(&res));
+                if let Some(length) = expected {
+                    transfers::set_total(id, length);
+                }
+                if let Some(responded) = responded {
+                    responded();
+                }
+                let etag = res.headers().get(hyper::header::ETAG).and_then(|value| value.to_str().ok()).map(|etag| etag.to_string());
+                save_body_to_file(id, res.into_body(), file_path, expected, etag)
+            }))
+    }));
 }
 
 /////////////////////////////////////////////////////////////////
 
 fn handle_received_msg(_: &connection_utils::TextConnection, msg : String) {
-    let title_size = std::cmp::min(msg.len(), 24usize);
-    console::Term::stdout().set_title(format!(">{}", &msg[..title_size]));
-    print(&msg);
+    if msg.starts_with(connection_utils::CONTROL_PREFIX) {
+        if !completion::handle_control(&msg) {
+            direct::handle_control(&msg);
+        }
+        return;
+    }
+    let msg = connection_utils::unescape_line(&msg);
+    let snippet: String = msg.lines().next().unwrap_or("").chars().take(24).collect();
+    match style::split_sender(&msg) {
+        Some((nick, text)) => transcript::log(transcript::MAIN_ROOM, Some(nick), text),
+        None => transcript::log(transcript::MAIN_ROOM, None, &msg),
+    }
+    match style::split_sender(&msg) {
+        Some((nick, text)) if !style::is_own(nick) => {
+            let mention = style::is_mention(text);
+            let title = count_unread(mention, &snippet);
+            if mention || style::is_highlight(text) {
+                request_attention(title);
+            }
+        },
+        _ => {
+            let unread = CONSOLE.lock().unwrap().unread();
+            console::Term::stdout().set_title(window_title(unread, &snippet));
+        },
+    }
+    print(&style::format_line(&msg));
+}
+
+pub const AWAY_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Tells the server when the user has not typed for `AWAY_AFTER` and when they type again.
+fn away_job(mut text_sender: connection_utils::Sender, rt: &mut tokio::runtime::Runtime) {
+    let mut away = false;
+    let job = tokio::timer::Interval::new(std::time::Instant::now() + AWAY_CHECK_INTERVAL, AWAY_CHECK_INTERVAL)
+        .map_err(|err| print(&format!(">>> Away timer error {:?}", err)))
+        .take_while(|_| Ok(!QUITTING.load(Ordering::SeqCst)))
+        .for_each(move |_| {
+            let idle = CONSOLE.lock().unwrap().idle() >= connection_utils::AWAY_AFTER;
+            if idle != away {
+                away = idle;
+                let line = connection_utils::control_line(&["AWAY", if away { "on" } else { "off" }]);
+                connection_utils::pass_line(&mut text_sender, line).map_err(|_| ())?;
+            }
+            Ok(())
+        });
+    rt.spawn(job);
+}
+
+pub const TITLE_FLASHES: u32 = 6;
+pub const TITLE_FLASH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Rings the bell and flashes the terminal title a few times.
+fn request_attention(title: String) {
+    print!("{}", ansi_escapes::Beep);
+    let flash = future::loop_fn(0, move |step| {
+        let shown = if step % 2 == 0 { format!("*** {}", &title) } else { title.clone() };
+        console::Term::stdout().set_title(shown);
+        tokio::timer::Delay::new(std::time::Instant::now() + TITLE_FLASH_INTERVAL)
+            .map(move |_| if step + 1 < TITLE_FLASHES { future::Loop::Continue(step + 1) } else { future::Loop::Break(()) })
+    }).map_err(|err| { print(&format!(">>> title flash error = {:?}", err)); });
+    tokio::spawn(flash);
+}
+
+/// The unread counter, if any, in front of the latest line, `@` marks a mention.
+fn window_title((unread, mentioned): (usize, bool), snippet: &str) -> String {
+    if unread == 0 {
+        return format!(">{}", snippet);
+    }
+    format!("({}{}) >{}", unread, if mentioned { "@" } else { "" }, snippet)
+}
+
+/// Updates the unread counter and returns the title showing it.
+fn count_unread(mention: bool, snippet: &str) -> String {
+    let unread = CONSOLE.lock().unwrap().add_unread(mention);
+    let title = window_title(unread, snippet);
+    console::Term::stdout().set_title(&title);
+    title
+}
+
+pub fn show_direct_message(from: &str, text: &str) {
+    transcript::log(&transcript::dm_room(from), Some(from), text);
+    request_attention(count_unread(true, &format!("DM {}", from)));
+    print(&style::format_direct(from, text, false));
 }
 
 fn text_protocol_job(receiver: connection_utils::Receiver, connect_addr: SocketAddr
     , rt: &mut tokio::runtime::Runtime) {
     print(&format!(">>> trying to connect with: {:?}", connect_addr));
+    update_status_bar(|bar| bar.state = "connecting");
     let start_connection = TcpStream::connect(&connect_addr)
         .and_then(move |socket| {
+            direct::set_local_ip(socket.local_addr()?.ip());
+            update_status_bar(|bar| bar.state = "connected");
             let connection = connection_utils::TextConnection::new(receiver, socket, Box::new(handle_received_msg))
-                .and_then(move |_|{ print(">>> DISCONNECTED"); Ok(())})
-                .map_err (move |e|{ print(&format!(">>> transfer error = {:?}", e)); });
+                .then(move |result| {
+                    match result {
+                        Ok(_) => print(">>> DISCONNECTED"),
+                        Err(e) => print(&format!(">>> transfer error = {:?}", e)),
+                    }
+                    update_status_bar(|bar| bar.state = "disconnected");
+                    if QUITTING.load(Ordering::SeqCst) {
+                        finish_quit();
+                    }
+                    Ok(())
+                });
             tokio::spawn(connection);
             Ok(())
         })
-        .map_err(move |err| { print(&format!(">>> connection error = {:?}", err)); });
+        .map_err(move |err| {
+            print(&format!(">>> connection error = {:?}", err));
+            update_status_bar(|bar| bar.state = "disconnected");
+        });
     rt.spawn(start_connection);
 }
 
 /////////////////////////////////////////////////////////////////
 
+pub const QUIT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+static QUITTING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// Fired once the connection closed after `:quit`, `main` stops the runtime then.
+    static ref QUIT: Mutex<Option<futures::sync::oneshot::Sender<()>>> = Mutex::new(None);
+}
+
+fn finish_quit() {
+    if let Some(quit) = QUIT.lock().expect("quit").take() {
+        let _ = quit.send(());
+    }
+}
+
+/// The input job ended and dropped its sender, without the direct one the connection flushes and closes,
+/// so the server sees the user leave. Gives up waiting after `QUIT_TIMEOUT`.
+fn start_quit() {
+    QUITTING.store(true, Ordering::SeqCst);
+    print(">>> Leaving...");
+    direct::shutdown();
+    let timeout = tokio::timer::Delay::new(std::time::Instant::now() + QUIT_TIMEOUT)
+        .then(|_| { finish_quit(); Ok(()) });
+    tokio::spawn(timeout);
+}
+
+fn send_chat_text(text_sender: &mut connection_utils::Sender, name: &str, text: &str) {
+    let line = connection_utils::escape_line(text);
+    if line.len() > connection_utils::MAX_SENT_LINE_LENGTH {
+        print(&format!(">>> Message not sent, {} bytes is over the limit of {}", line.len(), connection_utils::MAX_SENT_LINE_LENGTH));
+        return;
+    }
+    if let Err(e) = connection_utils::pass_line(text_sender, line) {
+        print(&format!("Cannot send, error: {}", e));
+    } else {
+        print(&style::format_line(&format!("{}: {}", name, text)));
+        transcript::log(transcript::MAIN_ROOM, Some(name), text);
+    }
+}
+
 fn input_job(name: String, mut text_sender: connection_utils::Sender, file_server_uri: String, rt: &mut tokio::runtime::Runtime) {
+    let mut compose: Option<Vec<String>> = None;
     let input_handler = connection_utils::InputReader::new(CONSOLE.clone())
         .for_each(move |line| {
-            if let Some(filename) = clientonly::parse_send_file(&line) {
-                spawn_send_single_file(filename, file_server_uri.clone());
-            } else if let Some(filename) = clientonly::parse_receive_file(&line) {
-                spawn_receive_file_request(&filename, &file_server_uri);
-            } else if let Err(e) = connection_utils::pass_line(&mut text_sender, line.clone()) {
-                print(&format!("Cannot send, error: {}", e));
+            let line = match compose.take() {
+                Some(mut block) => {
+                    if line == COMPOSE_ABORT {
+                        set_status(None);
+                        print(">>> Message discarded");
+                        return Ok(());
+                    }
+                    if line != COMPOSE_END {
+                        print(&format!("  | {}", &line));
+                        block.push(line);
+                        set_status(Some(format!(">>> Composing {} line(s), '{}' sends, '{}' discards", block.len(), COMPOSE_END, COMPOSE_ABORT)));
+                        compose = Some(block);
+                        return Ok(());
+                    }
+                    set_status(None);
+                    if !block.is_empty() {
+                        send_chat_text(&mut text_sender, &name, &block.join("\n"));
+                    }
+                    return Ok(());
+                },
+                None => line,
+            };
+            let escaped = format!("{}{}", commands::COMMAND_PREFIX, commands::COMMAND_PREFIX);
+            if line.starts_with(&escaped) {
+                send_chat_text(&mut text_sender, &name, &line[commands::COMMAND_PREFIX.len_utf8()..]);
+            } else if line.starts_with(commands::COMMAND_PREFIX) {
+                let mut context = commands::Context { name: &name, file_server_uri: &file_server_uri, compose: &mut compose, quit: false };
+                commands::execute(&mut context, &line);
+                if context.quit {
+                    return Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "quit"));
+                }
             } else {
-                print(&format!("{}: {}", &name, &line));
+                send_chat_text(&mut text_sender, &name, &line);
             }
             Ok(())
-        }).map_err(move |err| { print(&format!(">>> input error = {:?}", err)); });
+        }).map_err(move |err| {
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                start_quit();
+            } else {
+                print(&format!(">>> input error = {:?}", err));
+            }
+        });
     rt.spawn(input_handler);
 }
 
@@ -154,18 +460,42 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
-    let (name, server_ip_str) = clientonly::process_params();
-    if let Ok(server_ip4) = Ipv4Addr::from_str(&server_ip_str) {
-        let mut rt = Builder::new().build().unwrap();
-        let (mut text_sender, text_receiver) = futures::sync::mpsc::channel(connection_utils::CHANNEL_BUFF_SIZE);
-        let file_server_uri = format!("http://{}:{}/", &server_ip_str, connection_utils::SERVER_PORT_FILE);
-        let text_server_addr = SocketAddr::new(IpAddr::V4(server_ip4), connection_utils::SERVER_PORT_TEXT);
+    let (profile_name, profile) = config::load()?;
+    print(&format!(">>> profile: {}", profile_name));
+    print(&format!(">>> name: {}", profile.nick));
+    print(&format!(">>> other ip: {:?}", profile.host));
+    config::init(&profile);
+    console::set_colors_enabled(profile.colors);
+    style::init(&profile.nick, profile.highlights.clone());
+    transcript::init(&profile.host);
+    CONSOLE.lock().unwrap().set_history_file(clientonly::history_path());
+    CONSOLE.lock().unwrap().set_completer(Box::new(completion::complete));
+    CONSOLE.lock().unwrap().set_unread_cleared(Box::new(|| console::Term::stdout().set_title("con:")));
+    if console::Term::stdout().is_term() {
+        CONSOLE.lock().unwrap().enable_tui();
+    }
+    update_status_bar(|bar| { bar.nick = profile.nick.clone(); bar.server = profile.host.clone(); });
+    match (profile.host.as_str(), profile.text_port).to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(text_server_addr)) => {
+            let mut rt = Builder::new().build().unwrap();
+            let (mut text_sender, text_receiver) = futures::sync::mpsc::channel(connection_utils::CHANNEL_BUFF_SIZE);
+            let file_server_uri = profile.file_server_uri();
+
+            let (quit, quit_requested) = futures::sync::oneshot::channel();
+            *QUIT.lock().expect("quit") = Some(quit);
 
-        connection_utils::pass_line(&mut text_sender, name.clone()).unwrap(); //intoduce yourself
-        text_protocol_job(text_receiver, text_server_addr, &mut rt);
-        input_job(name, text_sender, file_server_uri, &mut rt);
+            connection_utils::pass_line(&mut text_sender, profile.nick.clone()).unwrap(); //intoduce yourself
+            direct::init(text_sender.clone(), file_server_uri.clone());
+            text_protocol_job(text_receiver, text_server_addr, &mut rt);
+            away_job(text_sender.clone(), &mut rt);
+            input_job(profile.nick, text_sender, file_server_uri, &mut rt);
 
-        rt.shutdown_on_idle().wait().unwrap();
-    } else { print(&format!(">>> wrong ip: {}", server_ip_str)); }
+            let _ = rt.block_on(quit_requested);
+            CONSOLE.lock().unwrap().disable_tui();
+            print(">>> Bye");
+            rt.shutdown_now().wait().unwrap();
+        },
+        _ => print(&format!(">>> wrong host: {}", profile.host)),
+    }
     Ok(())
 } 
\ No newline at end of file
@@ -0,0 +1,103 @@
+extern crate console;
+
+use console::{style, Color};
+use std::sync::Mutex;
+use std::string::String;
+
+const NICK_COLORS: [Color; 6] = [Color::Red, Color::Green, Color::Yellow, Color::Blue, Color::Magenta, Color::Cyan];
+
+struct StyleSettings {
+    own_nick: String,
+    highlights: Vec<String>,
+}
+
+lazy_static! {
+    static ref STYLE: Mutex<StyleSettings> = Mutex::new(StyleSettings { own_nick: String::new(), highlights: Vec::new() });
+}
+
+pub fn init(own_nick: &str, highlights: Vec<String>) {
+    let mut settings = STYLE.lock().expect("style");
+    settings.own_nick = own_nick.to_string();
+    settings.highlights = highlights.into_iter().map(|word| word.to_lowercase()).collect();
+}
+
+/// The same nick always gets the same color, FNV-1a over its bytes picks it.
+pub fn nick_color(nick: &str) -> Color {
+    let hash = nick.bytes().fold(0x811c_9dc5u32, |hash, byte| (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193));
+    NICK_COLORS[hash as usize % NICK_COLORS.len()]
+}
+
+/// `*bold*` and `` `code` `` spans, unmatched markers are left as they are.
+pub fn markup(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(['*', '`']) {
+        let marker = if rest[start..].starts_with('*') { '*' } else { '`' };
+        let after = &rest[start + 1..];
+        match after.find(marker) {
+            Some(end) if end > 0 => {
+                result.push_str(&rest[..start]);
+                let inner = &after[..end];
+                let styled = if marker == '*' { style(inner).bold() } else { style(inner).cyan() };
+                result.push_str(&styled.to_string());
+                rest = &after[end + 1..];
+            },
+            _ => {
+                result.push_str(&rest[..start + 1]);
+                rest = after;
+            },
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Splits a chat line into the sender and the text, `None` for system lines.
+pub fn split_sender(line: &str) -> Option<(&str, &str)> {
+    if line.starts_with(">>>") {
+        return None;
+    }
+    let pos = line.find(": ")?;
+    let (nick, text) = (&line[..pos], &line[pos + 2..]);
+    if nick.is_empty() || nick.contains(' ') {
+        return None;
+    }
+    Some((nick, text))
+}
+
+pub fn is_own(nick: &str) -> bool {
+    STYLE.lock().expect("style").own_nick == nick
+}
+
+pub fn is_highlight(text: &str) -> bool {
+    let text = text.to_lowercase();
+    STYLE.lock().expect("style").highlights.iter().any(|word| text.contains(word.as_str()))
+}
+
+/// Our nick as a whole word, ignoring case.
+pub fn is_mention(text: &str) -> bool {
+    let own_nick = STYLE.lock().expect("style").own_nick.to_lowercase();
+    if own_nick.is_empty() {
+        return false;
+    }
+    text.to_lowercase()
+        .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+        .any(|word| word == own_nick)
+}
+
+pub fn format_line(line: &str) -> String {
+    match split_sender(line) {
+        Some((nick, text)) => {
+            let nick_styled = if is_own(nick) { style(nick).bold().underlined() } else { style(nick).fg(nick_color(nick)).bold() };
+            let text = if !is_own(nick) && (is_highlight(text) || is_mention(text)) { style(markup(text)).reverse().to_string() } else { markup(text) };
+            format!("{}: {}", nick_styled, text)
+        },
+        None if line.starts_with(">>>") => style(line).yellow().dim().to_string(),
+        None => line.to_string(),
+    }
+}
+
+pub fn format_direct(nick: &str, text: &str, outgoing: bool) -> String {
+    let label = if outgoing { format!("[DM to {}]", nick) } else { format!("[DM from {}]", nick) };
+    format!("{} {}", style(label).magenta().bold(), markup(text))
+}
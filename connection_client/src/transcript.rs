@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::string::String;
+use crate::print;
+
+pub const LOGS_DIR: &str = "ConnectionLogs";
+pub const MAIN_ROOM: &str = "chat";
+
+type Date = (i64, u32, u32);
+
+/// Today's plain text and JSON lines files of a room.
+struct RoomFiles {
+    date: Date,
+    text: File,
+    json: File,
+}
+
+struct Transcript {
+    enabled: bool,
+    server: String,
+    rooms: HashMap<String, RoomFiles>,
+}
+
+lazy_static! {
+    static ref TRANSCRIPT: Mutex<Transcript> = Mutex::new(Transcript { enabled: false, server: String::new(), rooms: HashMap::new() });
+}
+
+pub fn init(server: &str) {
+    TRANSCRIPT.lock().expect("transcript").server = server.to_string();
+}
+
+pub fn set_enabled(enabled: bool) {
+    let mut transcript = TRANSCRIPT.lock().expect("transcript");
+    transcript.enabled = enabled;
+    transcript.rooms.clear();
+    drop(transcript);
+    print(&format!(">>> Transcript logging {} ({:?})", if enabled { "on" } else { "off" }, logs_dir()));
+}
+
+pub fn dm_room(nick: &str) -> String {
+    format!("dm-{}", nick)
+}
+
+fn logs_dir() -> PathBuf {
+    let mut dir = dirs::data_local_dir().unwrap();
+    dir.push(LOGS_DIR);
+    dir
+}
+
+/// UTC calendar date and time of `secs` since the epoch, days to date as in Hinnant's civil_from_days.
+fn utc_parts(secs: u64) -> ((i64, u32, u32), (u64, u64, u64)) {
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    let time = secs % 86400;
+    ((year, month, day), (time / 3600, time / 60 % 60, time % 60))
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn open_room(server: &str, room: &str, date: Date) -> std::io::Result<RoomFiles> {
+    let mut base = logs_dir();
+    base.push(server.replace(|c: char| !c.is_alphanumeric() && c != '.' && c != '-', "_"));
+    base.push(room.replace(['/', '\\', ':'], "_"));
+    std::fs::create_dir_all(&base)?;
+    base.push(format!("{:04}-{:02}-{:02}", date.0, date.1, date.2));
+    Ok(RoomFiles { date, text: open_append(&base.with_extension("log"))?, json: open_append(&base.with_extension("jsonl"))? })
+}
+
+/// Appends to today's plain text and JSON lines files of `room`, the files stay open until the UTC day changes.
+pub fn log(room: &str, from: Option<&str>, text: &str) {
+    let mut transcript = TRANSCRIPT.lock().expect("transcript");
+    if !transcript.enabled {
+        return;
+    }
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let ((year, month, day), (hour, minute, second)) = utc_parts(secs);
+    let text_line = match from {
+        Some(from) => format!("[{:02}:{:02}:{:02}] {}: {}", hour, minute, second, from, text),
+        None => format!("[{:02}:{:02}:{:02}] {}", hour, minute, second, text),
+    };
+    let json_line = json!({
+        "time": format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, minute, second),
+        "room": room,
+        "from": from,
+        "text": text,
+    }).to_string();
+
+    let date = (year, month, day);
+    let current = transcript.rooms.get(room).map(|files| files.date == date).unwrap_or(false);
+    if !current {
+        match open_room(&transcript.server, room, date) {
+            Ok(files) => { transcript.rooms.insert(room.to_string(), files); },
+            Err(e) => return stop(&mut transcript, e),
+        }
+    }
+    let files = transcript.rooms.get_mut(room).expect("open room");
+    let result = writeln!(files.text, "{}", text_line).and_then(|_| writeln!(files.json, "{}", json_line));
+    if let Err(e) = result {
+        stop(&mut transcript, e);
+    }
+}
+
+fn stop(transcript: &mut Transcript, e: std::io::Error) {
+    transcript.enabled = false;
+    transcript.rooms.clear();
+    print(&format!(">>> Transcript logging stopped, error: {}", e));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utc_parts_counts_from_the_epoch() {
+        assert_eq!(utc_parts(0), ((1970, 1, 1), (0, 0, 0)));
+        assert_eq!(utc_parts(951_868_800), ((2000, 3, 1), (0, 0, 0)));
+        assert_eq!(utc_parts(951_782_400 + 3661), ((2000, 2, 29), (1, 1, 1)));
+        assert_eq!(utc_parts(1_703_980_799), ((2023, 12, 30), (23, 59, 59)));
+    }
+}
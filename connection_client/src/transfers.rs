@@ -0,0 +1,206 @@
+extern crate tokio;
+extern crate futures;
+
+use tokio::prelude::*;
+use futures::future::Either;
+use futures::sync::oneshot;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::string::String;
+
+pub type TransferId = usize;
+pub type TransferFuture = Box<dyn Future<Item = (), Error = ()> + Send>;
+pub type StartFn = Box<dyn FnOnce(TransferId) -> TransferFuture + Send>;
+
+pub const MAX_ACTIVE_TRANSFERS: usize = 2;
+const STATUS_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Direction { Upload, Download }
+
+struct Transfer {
+    name: String,
+    direction: Direction,
+    total: Option<u64>,
+    done: u64,
+    started: Option<Instant>,
+    start: Option<StartFn>,
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+struct Transfers {
+    next_id: TransferId,
+    items: BTreeMap<TransferId, Transfer>,
+    queue: VecDeque<TransferId>,
+    last_status: Instant,
+}
+
+lazy_static! {
+    static ref TRANSFERS: Mutex<Transfers> = Mutex::new(Transfers {
+        next_id: 1,
+        items: BTreeMap::new(),
+        queue: VecDeque::new(),
+        last_status: Instant::now(),
+    });
+}
+
+/////////////////////////////////////////////////////////////////
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} {}", bytes, UNITS[0]) } else { format!("{:.1} {}", value, UNITS[unit]) }
+}
+
+impl Transfer {
+    fn describe(&self, id: TransferId) -> String {
+        let arrow = if self.direction == Direction::Upload { "up" } else { "down" };
+        let started = match self.started {
+            Some(started) => started,
+            None => return format!("[{}] {} {} queued", id, arrow, self.name),
+        };
+        let elapsed = started.elapsed().as_millis() as f64 / 1000.0;
+        let rate = if elapsed > 0.0 { self.done as f64 / elapsed } else { 0.0 };
+        let mut result = format!("[{}] {} {} {}", id, arrow, self.name, format_bytes(self.done));
+        if let Some(total) = self.total {
+            let percent = (self.done * 100).checked_div(total).unwrap_or(100);
+            result += &format!("/{} {}%", format_bytes(total), percent);
+        }
+        result += &format!(" {}/s", format_bytes(rate as u64));
+        if let (Some(total), true) = (self.total, rate > 0.0) {
+            let eta = total.saturating_sub(self.done) as f64 / rate;
+            result += &format!(" ETA {}s", eta.ceil() as u64);
+        }
+        result
+    }
+}
+
+impl Transfers {
+    fn status(&self) -> Option<String> {
+        let active: Vec<String> = self.items.iter()
+            .filter(|(_, transfer)| transfer.started.is_some())
+            .map(|(id, transfer)| transfer.describe(*id))
+            .collect();
+        if active.is_empty() {
+            return None;
+        }
+        let mut status = active.join(" | ");
+        if !self.queue.is_empty() {
+            status += &format!(" (+{} queued)", self.queue.len());
+        }
+        Some(status)
+    }
+
+    fn active_count(&self) -> usize {
+        self.items.values().filter(|transfer| transfer.started.is_some()).count()
+    }
+}
+
+/////////////////////////////////////////////////////////////////
+
+fn refresh_status() {
+    let status = {
+        let mut transfers = TRANSFERS.lock().expect("transfers");
+        transfers.last_status = Instant::now();
+        transfers.status()
+    };
+    crate::set_status(status);
+}
+
+fn start_pending() {
+    let mut to_start = Vec::new();
+    {
+        let mut transfers = TRANSFERS.lock().expect("transfers");
+        while transfers.active_count() < MAX_ACTIVE_TRANSFERS {
+            let id = match transfers.queue.pop_front() {
+                Some(id) => id,
+                None => break,
+            };
+            let (cancel_sender, cancel_receiver) = oneshot::channel();
+            let transfer = transfers.items.get_mut(&id).expect("queued transfer");
+            transfer.started = Some(Instant::now());
+            transfer.cancel = Some(cancel_sender);
+            to_start.push((id, transfer.start.take().expect("start function"), cancel_receiver));
+        }
+    }
+    for (id, start, cancel_receiver) in to_start {
+        let task = start(id).select2(cancel_receiver)
+            .then(move |result| {
+                if let Ok(Either::B(_)) = result {
+                    crate::print(&format!(">>> Transfer [{}] cancelled", id));
+                }
+                finish(id);
+                Ok(())
+            });
+        tokio::spawn(task);
+    }
+    refresh_status();
+}
+
+fn finish(id: TransferId) {
+    TRANSFERS.lock().expect("transfers").items.remove(&id);
+    start_pending();
+}
+
+pub fn enqueue(name: String, direction: Direction, start: StartFn) -> TransferId {
+    let id = {
+        let mut transfers = TRANSFERS.lock().expect("transfers");
+        let id = transfers.next_id;
+        transfers.next_id += 1;
+        transfers.items.insert(id, Transfer { name, direction, total: None, done: 0, started: None, start: Some(start), cancel: None });
+        transfers.queue.push_back(id);
+        id
+    };
+    start_pending();
+    id
+}
+
+pub fn set_total(id: TransferId, total: u64) {
+    if let Some(transfer) = TRANSFERS.lock().expect("transfers").items.get_mut(&id) {
+        transfer.total = Some(total);
+    }
+}
+
+pub fn add_progress(id: TransferId, bytes: u64) {
+    let refresh = {
+        let mut transfers = TRANSFERS.lock().expect("transfers");
+        if let Some(transfer) = transfers.items.get_mut(&id) {
+            transfer.done += bytes;
+        }
+        transfers.last_status.elapsed() >= STATUS_INTERVAL
+    };
+    if refresh {
+        refresh_status();
+    }
+}
+
+pub fn cancel(id: TransferId) -> bool {
+    {
+        let mut transfers = TRANSFERS.lock().expect("transfers");
+        let transfer = match transfers.items.get_mut(&id) {
+            Some(transfer) => transfer,
+            None => return false,
+        };
+        if transfer.started.is_some() {
+            if let Some(sender) = transfer.cancel.take() {
+                let _ = sender.send(());
+            }
+        } else {
+            transfers.queue.retain(|queued| *queued != id);
+            transfers.items.remove(&id);
+        }
+    }
+    refresh_status();
+    true
+}
+
+pub fn list() -> Vec<String> {
+    let transfers = TRANSFERS.lock().expect("transfers");
+    transfers.items.iter().map(|(id, transfer)| transfer.describe(*id)).collect()
+}
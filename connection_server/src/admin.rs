@@ -0,0 +1,83 @@
+extern crate tokio;
+extern crate futures;
+
+use tokio::prelude::*;
+use std::time::{Duration, Instant};
+use std::string::String;
+use crate::print;
+
+pub const SHUTDOWN_DELAY: Duration = Duration::from_millis(500);
+
+fn who() {
+    let clients = crate::PEERS.lock().expect("State lock who");
+    if clients.is_empty() {
+        print(">>> Nobody is connected");
+    }
+    for (addr, (_, name)) in clients.iter() {
+        let away = if crate::is_away(addr) { " (away)" } else { "" };
+        print(&format!(">>> {} {:?}{}", name.as_ref().map(|n| n.as_str()).unwrap_or("<unnamed>"), addr, away));
+    }
+}
+
+fn say(text: &str) {
+    crate::broadcast_line(format!("server: {}", text));
+    crate::events::publish(crate::events::ChatEvent::Message { from: "server".to_string(), text: text.to_string() });
+}
+
+fn clear_history() {
+    crate::HISTORY.lock().expect("history clear").lines.clear();
+    print(">>> History cleared");
+}
+
+fn list_files() {
+    match crate::stored_files() {
+        Ok(files) => {
+            for (name, size) in &files {
+                print(&format!(">>> {} ({} B)", name, size));
+            }
+            print(&format!(">>> {} file(s)", files.len()));
+        },
+        Err(e) => print(&format!(">>> Cannot list files: {}", e)),
+    }
+}
+
+fn shutdown() {
+    crate::broadcast_line(">>> Server is shutting down".to_string());
+    let task = tokio::timer::Delay::new(Instant::now() + SHUTDOWN_DELAY)
+        .then(|_| -> Result<(), ()> { std::process::exit(0) });
+    tokio::spawn(task);
+}
+
+fn handle_admin_line(line: &str) {
+    let (command, argument) = match line.find(' ') {
+        Some(pos) => (&line[..pos], line[pos + 1..].trim()),
+        None => (line, ""),
+    };
+    if let Some(reply) = crate::moderation::handle_command(line) {
+        print(&reply);
+        return;
+    }
+    match (command, argument) {
+        ("/who", "") => who(),
+        ("/say", text) if !text.is_empty() => say(text),
+        ("/history", "clear") => clear_history(),
+        ("/files", "") => list_files(),
+        ("/shutdown", "") => shutdown(),
+        _ => {
+            print(">>> Commands: /who, /say <text>, /history clear, /files, /shutdown");
+            print(crate::moderation::MODERATION_HELP);
+        },
+    }
+}
+
+pub fn admin_job(rt: &mut tokio::runtime::Runtime) {
+    let input_handler = connection_utils::InputReader::new(crate::CONSOLE.clone())
+        .for_each(|line| {
+            let line: String = line.trim().to_string();
+            if !line.is_empty() {
+                handle_admin_line(&line);
+            }
+            Ok(())
+        }).map_err(move |err| { print(&format!(">>> input error = {:?}", err)); });
+    rt.spawn(input_handler);
+}
@@ -0,0 +1,62 @@
+extern crate tokio;
+extern crate futures;
+extern crate hyper;
+
+use tokio::prelude::*;
+use crate::{print, respond, ResponseFuture, HISTORY_SIZE};
+use crate::config::CONFIG;
+use crate::web::{chat_text, json_response, query_param, read_json_body, single_line, MAX_NAME_LENGTH};
+
+pub const API_PATH: &str = "/api/";
+
+fn is_authorized(request: &hyper::Request<hyper::Body>) -> bool {
+    let token = request.headers().get(hyper::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer ")).map(str::trim);
+    match token {
+        Some(token) => CONFIG.api_tokens.iter().any(|allowed| allowed == token),
+        None => false,
+    }
+}
+
+fn recent_messages(query: Option<&str>) -> hyper::Response<hyper::Body> {
+    let limit = query_param(query, "limit").and_then(|v| v.parse().ok()).unwrap_or(HISTORY_SIZE);
+    let history = crate::HISTORY.lock().expect("history").since(0);
+    let skip = history.len().saturating_sub(limit);
+    let messages: Vec<serde_json::Value> = history.into_iter().skip(skip)
+        .map(|(id, text)| json!({ "id": id, "text": connection_utils::unescape_line(&text) }))
+        .collect();
+    json_response(hyper::http::StatusCode::OK, json!({ "messages": messages }))
+}
+
+fn post_bot_message(body: hyper::Body) -> ResponseFuture {
+    Box::new(read_json_body(body).map(|value| {
+        let field = |key| value.as_ref().and_then(|v| v.get(key)).and_then(|v| v.as_str()).unwrap_or_default();
+        let (bot, text) = (single_line(field("bot")), chat_text(field("text")));
+        if bot.is_empty() || text.is_empty() || bot.chars().count() > MAX_NAME_LENGTH || bot.contains(' ') {
+            return json_response(hyper::http::StatusCode::BAD_REQUEST, json!({ "error": "expected non-empty 'bot' and 'text'" }));
+        }
+        if !crate::moderation::may_post(&bot) {
+            return json_response(hyper::http::StatusCode::FORBIDDEN, json!({ "error": "banned or muted" }));
+        }
+        let from = format!("{}@bot", bot);
+        crate::broadcast_line(format!("{}: {}", &from, &text));
+        crate::events::publish(crate::events::ChatEvent::Message { from, text: connection_utils::unescape_line(&text) });
+        respond(hyper::http::StatusCode::NO_CONTENT, hyper::Body::empty())
+    }))
+}
+
+pub fn handle_api_request(request: hyper::Request<hyper::Body>) -> ResponseFuture {
+    if !is_authorized(&request) {
+        print(&format!(">>> Unauthorized API request: {}", request.uri().path()));
+        return Box::new(future::ok(hyper::Response::builder().status(hyper::http::StatusCode::UNAUTHORIZED)
+            .header(hyper::header::WWW_AUTHENTICATE, "Bearer").body(hyper::Body::empty()).unwrap()));
+    }
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    match (method, path.as_str()) {
+        (hyper::Method::GET, "/api/messages") => Box::new(future::ok(recent_messages(request.uri().query()))),
+        (hyper::Method::POST, "/api/messages") => post_bot_message(request.into_body()),
+        _ => Box::new(future::ok(respond(hyper::http::StatusCode::NOT_FOUND, hyper::Body::empty()))),
+    }
+}
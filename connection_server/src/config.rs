@@ -0,0 +1,96 @@
+extern crate toml;
+
+use std::path::PathBuf;
+use std::string::String;
+use crate::print;
+
+pub const CONFIG_FILE: &str = "ConnectionServer.toml";
+
+#[derive(Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default)]
+    pub events: Vec<String>,
+    pub pattern: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub lines_per_second: f64,
+    pub line_burst: f64,
+    pub bytes_per_second: f64,
+    pub byte_burst: f64,
+    pub warn_after: u32,
+    pub disconnect_after: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            lines_per_second: 5.0,
+            line_burst: 20.0,
+            bytes_per_second: 4096.0,
+            byte_burst: 16384.0,
+            warn_after: 10,
+            disconnect_after: 50,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn to_limit(&self) -> connection_utils::RateLimit {
+        connection_utils::RateLimit {
+            lines_per_second: self.lines_per_second,
+            line_burst: self.line_burst,
+            bytes_per_second: self.bytes_per_second,
+            byte_burst: self.byte_burst,
+            warn_after: self.warn_after,
+            disconnect_after: self.disconnect_after,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub api_tokens: Vec<String>,
+    pub webhooks: Vec<WebhookConfig>,
+    pub irc_port: Option<u16>,
+    pub operator_password: Option<String>,
+    pub rate_limit: RateLimitConfig,
+    pub max_line_length: Option<usize>,
+}
+
+pub fn config_path() -> PathBuf {
+    let mut file_path = dirs::data_local_dir().unwrap();
+    file_path.push(CONFIG_FILE);
+    file_path
+}
+
+fn load() -> ServerConfig {
+    let file_path = config_path();
+    if !file_path.is_file() {
+        print(&format!(">>> No config at {:?}, using defaults", &file_path));
+        return ServerConfig::default();
+    }
+    let parsed = std::fs::read_to_string(&file_path)
+        .map_err(|e| e.to_string())
+        .and_then(|content| toml::from_str(&content).map_err(|e| e.to_string()));
+    match parsed {
+        Ok(config) => { print(&format!(">>> config: {:?}", &file_path)); config },
+        Err(e) => { print(&format!(">>> Wrong config {:?}: {}", &file_path, e)); ServerConfig::default() },
+    }
+}
+
+impl ServerConfig {
+    /// Never above what clients read once the sender's name is in front of the line.
+    pub fn max_line_length(&self) -> usize {
+        let limit = connection_utils::MAX_SENT_LINE_LENGTH;
+        self.max_line_length.map(|max| max.min(limit)).unwrap_or(limit)
+    }
+}
+
+lazy_static! {
+    pub static ref CONFIG: ServerConfig = load();
+}
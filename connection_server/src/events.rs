@@ -0,0 +1,72 @@
+extern crate tokio;
+extern crate futures;
+extern crate bytes;
+extern crate hyper;
+
+use tokio::prelude::*;
+use std::sync::Mutex;
+use std::string::String;
+use crate::ResponseFuture;
+
+pub const EVENTS_PATH: &str = "/events";
+
+pub enum ChatEvent {
+    Message { from: String, text: String },
+    Join { name: String },
+    Leave { name: String },
+    File { name: String },
+}
+
+impl ChatEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ChatEvent::Message { .. } => "message",
+            ChatEvent::Join { .. } => "join",
+            ChatEvent::Leave { .. } => "leave",
+            ChatEvent::File { .. } => "file",
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            ChatEvent::Message { from, text } => json!({ "type": self.kind(), "from": from, "text": text }),
+            ChatEvent::Join { name } | ChatEvent::Leave { name } => json!({ "type": self.kind(), "name": name }),
+            ChatEvent::File { name } => json!({ "type": self.kind(), "file": name }),
+        }
+    }
+}
+
+lazy_static! {
+    static ref SUBSCRIBERS: Mutex<Vec<connection_utils::Sender>> = Mutex::new(Vec::new());
+}
+
+pub fn publish(event: ChatEvent) {
+    crate::webhooks::dispatch(&event);
+    let frame = bytes::Bytes::from(format!("event: {}\ndata: {}\n\n", event.kind(), event.to_json()));
+    let mut subscribers = SUBSCRIBERS.lock().expect("subscribers");
+    let mut open = Vec::with_capacity(subscribers.len());
+    for mut subscriber in subscribers.drain(..) {
+        match subscriber.try_send(frame.clone()) {
+            Err(ref e) if e.is_disconnected() => {},
+            _ => open.push(subscriber),
+        }
+    }
+    *subscribers = open;
+}
+
+pub fn handle_events_request(request: hyper::Request<hyper::Body>) -> ResponseFuture {
+    if request.method() != hyper::Method::GET {
+        return Box::new(future::ok(hyper::Response::builder().status(hyper::http::StatusCode::METHOD_NOT_ALLOWED)
+            .header("Allow", "GET").body(hyper::Body::empty()).unwrap()));
+    }
+    let (sender, receiver) = futures::sync::mpsc::channel(connection_utils::CHANNEL_BUFF_SIZE);
+    SUBSCRIBERS.lock().expect("subscribers").push(sender);
+    let body = stream::once(Ok(bytes::Bytes::from(": connected\n\n")))
+        .chain(receiver)
+        .map(hyper::Chunk::from)
+        .map_err(|_| std::io::Error::other("event stream closed"));
+    Box::new(future::ok(hyper::Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "text/event-stream")
+        .header(hyper::header::CACHE_CONTROL, "no-cache")
+        .body(hyper::Body::wrap_stream(body)).unwrap()))
+}
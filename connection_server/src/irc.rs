@@ -0,0 +1,247 @@
+extern crate tokio;
+extern crate futures;
+extern crate bytes;
+
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::string::String;
+use connection_utils::TextConnection;
+use crate::print;
+
+pub const IRC_SERVER_NAME: &str = "connection";
+pub const IRC_CHANNEL: &str = "#connection";
+
+struct IrcSession {
+    sender: connection_utils::Sender,
+    nick: Option<String>,
+    user: bool,
+    joined: bool,
+}
+
+lazy_static! {
+    static ref SESSIONS: Mutex<HashMap<SocketAddr, IrcSession>> = Mutex::new(HashMap::new());
+}
+
+fn prefix(nick: &str) -> String {
+    format!(":{}!{}@{}", nick, nick, IRC_SERVER_NAME)
+}
+
+fn reply(session: &mut IrcSession, line: String) {
+    if let Err(e) = connection_utils::pass_line(&mut session.sender, line) {
+        print(&format!(">>> IRC send error: {}", e));
+    }
+}
+
+fn numeric(session: &mut IrcSession, code: &str, text: &str) {
+    let nick = session.nick.clone().unwrap_or_else(|| "*".to_string());
+    reply(session, format!(":{} {} {} {}", IRC_SERVER_NAME, code, nick, text));
+}
+
+/// Turns a chat line as sent to `connection_client` into IRC messages, one per line of a multi-line message.
+fn chat_to_irc(line: &str) -> Vec<String> {
+    if line.starts_with(connection_utils::CONTROL_PREFIX) {
+        return Vec::new();
+    }
+    let line = connection_utils::unescape_line(line);
+    if !line.starts_with(">>>") {
+        if let Some(pos) = line.find(": ") {
+            let (name, text) = (&line[..pos], &line[pos + 2..]);
+            if !name.is_empty() && !name.contains(' ') {
+                return text.lines().map(|text| format!("{} PRIVMSG {} :{}", prefix(name), IRC_CHANNEL, text)).collect();
+            }
+        }
+    }
+    line.lines().map(|text| format!(":{} NOTICE {} :{}", IRC_SERVER_NAME, IRC_CHANNEL, text)).collect()
+}
+
+fn split_params(line: &str) -> (String, Vec<String>) {
+    let (head, trailing) = match line.find(" :") {
+        Some(pos) => (&line[..pos], Some(&line[pos + 2..])),
+        None => (line, None),
+    };
+    let mut words = head.split_whitespace().map(|word| word.to_string());
+    let command = words.next().unwrap_or_default().to_uppercase();
+    let mut params: Vec<String> = words.collect();
+    if let Some(trailing) = trailing {
+        params.push(trailing.to_string());
+    }
+    (command, params)
+}
+
+/// CTCP requests start with the control prefix of the chat, an ACTION becomes `* nick text` and the rest is dropped.
+fn ctcp_to_chat(nick: &str, text: &str) -> Option<String> {
+    if !text.starts_with(connection_utils::CONTROL_PREFIX) {
+        return Some(text.to_string());
+    }
+    let request = text.trim_matches(connection_utils::CONTROL_PREFIX);
+    let mut words = request.splitn(2, ' ');
+    match (words.next(), words.next().map(|action| action.trim())) {
+        (Some("ACTION"), Some(action)) if !action.is_empty() => Some(format!("* {} {}", nick, action)),
+        _ => None,
+    }
+}
+
+/////////////////////////////////////////////////////////////////
+
+fn nick_in_use(nick: &str) -> bool {
+    crate::PEERS.lock().expect("State lock irc").values()
+        .any(|(_, name)| name.as_ref().map(|n| n.as_str()) == Some(nick))
+}
+
+fn join_chat(addr: SocketAddr, session: &mut IrcSession) {
+    let nick = session.nick.clone().expect("registered nick");
+    let irc_sender = session.sender.clone();
+    let receiver = crate::handle_add_user(&addr);
+    let forward = receiver
+        .map(|line| {
+            let lines = chat_to_irc(connection_utils::bytes_to_str(&line).trim_end());
+            stream::iter_ok::<_, ()>(lines.into_iter().map(|line| bytes::Bytes::from(line + "\r\n")))
+        })
+        .flatten()
+        .forward(irc_sender.sink_map_err(|_| ()))
+        .then(move |_| {
+            end_removed_session(addr);
+            Ok(())
+        });
+    tokio::spawn(forward);
+
+    session.joined = true;
+    reply(session, format!("{} JOIN {}", prefix(&nick), IRC_CHANNEL));
+    let names = crate::PEERS.lock().expect("State lock irc").values()
+        .filter_map(|(_, name)| name.clone())
+        .collect::<Vec<String>>()
+        .join(" ");
+    numeric(session, "353", &format!("= {} :{} {}", IRC_CHANNEL, nick, names));
+    numeric(session, "366", &format!("{} :End of /NAMES list", IRC_CHANNEL));
+    crate::handle_line(addr, nick);
+}
+
+/// The chat ended the receiver of a session that is still joined, the user was kicked, so the link is closed too.
+fn end_removed_session(addr: SocketAddr) {
+    let mut sessions = SESSIONS.lock().expect("irc sessions");
+    let joined = sessions.get(&addr).map(|session| session.joined).unwrap_or(false);
+    if joined {
+        let mut session = sessions.remove(&addr).expect("known session");
+        reply(&mut session, "ERROR :Closing link, removed from the chat".to_string());
+    }
+}
+
+fn leave_chat(addr: &SocketAddr, session: &mut IrcSession) {
+    if session.joined {
+        session.joined = false;
+        crate::handle_removed_user(addr);
+    }
+}
+
+fn handle_command(addr: SocketAddr, session: &mut IrcSession, command: &str, params: &[String]) {
+    let registered = session.nick.is_some() && session.user;
+    match command {
+        "CAP" => reply(session, format!(":{} CAP * LS :", IRC_SERVER_NAME)),
+        "PING" => reply(session, format!(":{} PONG {} :{}", IRC_SERVER_NAME, IRC_SERVER_NAME, params.first().map(|p| p.as_str()).unwrap_or(""))),
+        "PONG" => {},
+        "NICK" if registered => numeric(session, "484", ":Nick changes are not supported"),
+        "NICK" => match params.first() {
+            Some(nick) if nick_in_use(nick) => numeric(session, "433", &format!("{} :Nickname is already in use", nick)),
+            Some(nick) => session.nick = Some(nick.clone()),
+            None => numeric(session, "431", ":No nickname given"),
+        },
+        "USER" => session.user = true,
+        _ if !registered => numeric(session, "451", ":You have not registered"),
+        "JOIN" if params.first().map(|c| c.as_str()) == Some(IRC_CHANNEL) => {
+            if !session.joined {
+                join_chat(addr, session);
+            }
+        },
+        "JOIN" => numeric(session, "403", &format!("{} :Only {} is available", params.first().map(|p| p.as_str()).unwrap_or(""), IRC_CHANNEL)),
+        "PRIVMSG" | "NOTICE" => match (params.first(), params.get(1)) {
+            (Some(target), Some(text)) if target == IRC_CHANNEL && session.joined => {
+                if let Some(text) = ctcp_to_chat(session.nick.as_ref().expect("registered nick"), text) {
+                    crate::handle_line(addr, connection_utils::escape_line(&text));
+                }
+            },
+            (Some(target), Some(_)) => numeric(session, "404", &format!("{} :Cannot send to {}", target, target)),
+            _ => numeric(session, "412", ":No text to send"),
+        },
+        "PART" => {
+            let nick = session.nick.clone().expect("registered nick");
+            reply(session, format!("{} PART {}", prefix(&nick), IRC_CHANNEL));
+            leave_chat(&addr, session);
+        },
+        "MODE" | "WHO" => numeric(session, "315", &format!("{} :End of /WHO list", IRC_CHANNEL)),
+        _ => numeric(session, "421", &format!("{} :Unknown command", command)),
+    }
+
+    if !registered && session.nick.is_some() && session.user {
+        numeric(session, "001", &format!(":Welcome to {}, join {} to chat", IRC_SERVER_NAME, IRC_CHANNEL));
+        numeric(session, "422", ":MOTD File is missing");
+    }
+}
+
+fn handle_irc_line(connection: &TextConnection, line: String) {
+    let addr = connection.lines.socket.peer_addr().expect("Socket address irc");
+    let (command, params) = split_params(&line);
+    let mut sessions = SESSIONS.lock().expect("irc sessions");
+    if command == "QUIT" {
+        if let Some(mut session) = sessions.remove(&addr) {
+            leave_chat(&addr, &mut session);
+        }
+        return;
+    }
+    if let Some(session) = sessions.get_mut(&addr) {
+        handle_command(addr, session, &command, &params);
+    }
+}
+
+pub fn handle_irc_connection(socket: TcpStream) -> Result<(), std::io::Error> {
+    let addr = socket.peer_addr()?;
+    let (mut sender, receiver) = futures::sync::mpsc::channel(connection_utils::CHANNEL_BUFF_SIZE);
+    if crate::moderation::is_banned_ip(&addr.ip()) {
+        let _ = connection_utils::pass_line(&mut sender, "ERROR :You are banned".to_string());
+        print(&format!(">>> Banned IRC address {:?} refused", &addr));
+        let con = TextConnection::new(receiver, socket, Box::new(|_, _| {}))
+            .map_err(move |e| { print(&format!(">>> IRC transfer error = {:?}", e)); });
+        tokio::spawn(con);
+        return Ok(());
+    }
+    SESSIONS.lock().expect("irc sessions").insert(addr, IrcSession { sender, nick: None, user: false, joined: false });
+    print(&format!(">>> IRC client {} connected", &addr));
+    let con = TextConnection::new(receiver, socket, Box::new(handle_irc_line))
+        .with_max_line_length(crate::config::CONFIG.max_line_length())
+        .with_rate_limit(crate::config::CONFIG.rate_limit.to_limit())
+        .then(move |result| {
+            if let Err(e) = result {
+                print(&format!(">>> IRC transfer error = {:?}", e));
+            }
+            if let Some(mut session) = SESSIONS.lock().expect("irc sessions").remove(&addr) {
+                leave_chat(&addr, &mut session);
+            }
+            Ok(())
+        });
+    tokio::spawn(con);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ctcp_action_becomes_a_chat_line() {
+        assert_eq!(ctcp_to_chat("bob", "\u{1}ACTION waves\u{1}"), Some("* bob waves".to_string()));
+        assert_eq!(ctcp_to_chat("bob", "\u{1}ACTION waves"), Some("* bob waves".to_string()));
+    }
+
+    #[test]
+    fn other_ctcp_requests_are_dropped() {
+        assert_eq!(ctcp_to_chat("bob", "\u{1}VERSION\u{1}"), None);
+        assert_eq!(ctcp_to_chat("bob", "\u{1}ACTION\u{1}"), None);
+    }
+
+    #[test]
+    fn plain_text_is_kept() {
+        assert_eq!(ctcp_to_chat("bob", "hello :)"), Some("hello :)".to_string()));
+    }
+}
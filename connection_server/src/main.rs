@@ -6,6 +6,28 @@ extern crate connection_utils;
 extern crate lazy_static;
 extern crate hyper;
 extern crate dirs;
+extern crate mime_guess;
+extern crate httpdate;
+#[macro_use]
+extern crate serde_json;
+extern crate sha1;
+extern crate base64;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+extern crate regex;
+
+mod config;
+mod relay;
+mod web;
+mod ws;
+mod api;
+mod events;
+mod webhooks;
+mod irc;
+mod admin;
+mod moderation;
 
 use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
@@ -14,29 +36,132 @@ use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::string::String;
 use std::net::IpAddr;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use connection_utils::TextConnection;
 use std::collections::VecDeque;
+use events::ChatEvent;
 
 pub const HISTORY_SIZE: usize = 16;
+pub const FILES_LINE_LENGTH: usize = 4 * 1024;
 pub type Clients = HashMap<SocketAddr, (connection_utils::Sender, Option<String>)>;
+pub type ResponseFuture = Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = hyper::Error> + Send>;
+
+pub struct History {
+    lines: VecDeque<String>,
+    total: usize,
+}
+
+impl History {
+    pub fn since(&self, last_id: usize) -> Vec<(usize, String)> {
+        let first_id = self.total - self.lines.len() + 1;
+        self.lines.iter().enumerate()
+            .map(|(i, line)| (first_id + i, line.clone()))
+            .filter(|(id, _)| *id > last_id)
+            .collect()
+    }
+}
 
 lazy_static! { 
     static ref CONSOLE: connection_utils::SafeConsole = Arc::new(Mutex::new(connection_utils::ConsoleBuf::new())); 
     static ref PEERS: Arc<Mutex<Clients>> = Arc::new(Mutex::new(HashMap::new()));
-    static ref HISTORY: Arc<Mutex<VecDeque<String>>> = Arc::new(Mutex::new(VecDeque::with_capacity(HISTORY_SIZE)));
+    static ref AWAY: Mutex<HashSet<SocketAddr>> = Mutex::new(HashSet::new());
+    static ref HISTORY: Arc<Mutex<History>> = Arc::new(Mutex::new(History { lines: VecDeque::with_capacity(HISTORY_SIZE), total: 0 }));
 }
 
 pub fn print(line : &str) { CONSOLE.lock().unwrap().cprint(line); }
 
+pub fn respond(status: hyper::http::StatusCode, body: hyper::Body) -> hyper::Response<hyper::Body> {
+    hyper::Response::builder().status(status).body(body).unwrap()
+}
+
 //////////////////////////////////////////////////////////////////////////////////////////////
 
 fn push_history(msg: String){
     let mut local_history = HISTORY.lock().expect("history");
-    if local_history.len() == HISTORY_SIZE {
-        local_history.pop_front();
+    if local_history.lines.len() == HISTORY_SIZE {
+        local_history.lines.pop_front();
     } 
-    local_history.push_back(msg);
+    local_history.lines.push_back(msg);
+    local_history.total += 1;
+}
+
+pub fn broadcast_line(msg: String) {
+    let mut clients = PEERS.lock().expect("clients");
+    for (sender, name) in clients.values_mut() {
+        if name.is_some() {
+            let _ = connection_utils::pass_line(sender, msg.clone());
+        }
+    }
+    print(&msg);
+    push_history(msg);
+}
+
+fn send_to_named(clients: &mut Clients, line: &str) {
+    for (_, (sender, name)) in clients.iter_mut() {
+        if name.is_some() {
+            let _ = connection_utils::pass_line(sender, line.to_string());
+        }
+    }
+}
+
+pub fn is_away(addr: &SocketAddr) -> bool {
+    AWAY.lock().expect("away").contains(addr)
+}
+
+/// Control line with the sorted nicknames, clients use it for completion, idle users carry `AWAY_SUFFIX`.
+fn members_line(clients: &Clients) -> String {
+    let mut names: Vec<String> = clients.iter()
+        .filter_map(|(addr, (_, name))| name.as_ref().map(|name| {
+            if is_away(addr) { format!("{}{}", name, connection_utils::AWAY_SUFFIX) } else { name.clone() }
+        }))
+        .collect();
+    names.sort();
+    let mut fields = vec!["MEMBERS"];
+    fields.extend(names.iter().map(|name| name.as_str()));
+    connection_utils::control_line(&fields)
+}
+
+pub fn stored_files() -> std::io::Result<Vec<(String, u64)>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dirs::download_dir().unwrap())?.filter_map(Result::ok) {
+        if let Ok(metadata) = entry.metadata() {
+            if metadata.is_file() {
+                files.push((entry.file_name().to_string_lossy().to_string(), metadata.len()));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Control lines with the percent encoded names of the files on the server, `FILES` starts the list
+/// and `FILES+` lines continue it so a long listing never gets near the maximum line length.
+fn files_lines() -> Vec<String> {
+    let names: Vec<String> = stored_files().unwrap_or_default().iter().map(|(name, _)| connection_utils::percent_encode(name)).collect();
+    let mut lines = Vec::new();
+    let mut fields = vec!["FILES"];
+    let mut length = 0;
+    for name in &names {
+        if fields.len() > 1 && length + name.len() + 1 > FILES_LINE_LENGTH {
+            lines.push(connection_utils::control_line(&fields));
+            fields = vec!["FILES+"];
+            length = 0;
+        }
+        fields.push(name);
+        length += name.len() + 1;
+    }
+    lines.push(connection_utils::control_line(&fields));
+    lines
+}
+
+/// Sends the file list once the caller released `PEERS`, reading the directory can take a while.
+fn send_files(mut sender: connection_utils::Sender) {
+    tokio::spawn(future::lazy(move || {
+        for line in files_lines() {
+            let _ = connection_utils::pass_line(&mut sender, line);
+        }
+        Ok(())
+    }));
 }
 
 fn list_clients(clients: &Clients, excluded_adds: &SocketAddr) -> String {
@@ -56,11 +181,13 @@ fn handle_new_named_user(clients: &mut Clients, addr: &SocketAddr, msg: String)
     let list_str = list_clients(&clients, &addr);
     let (sender, out_name) = clients.get_mut(&addr).expect("Known address");
     let out_msg = format!(">>> New user: {} {:?}", &msg, &addr);
+    events::publish(ChatEvent::Join { name: msg.clone() });
     *out_name = Some(msg);
-    for line in HISTORY.lock().expect("history1").iter() {
+    for line in HISTORY.lock().expect("history1").lines.iter() {
         connection_utils::pass_line(sender, line.clone()).expect("Pass msg0");
     }
     connection_utils::pass_line(sender, format!(">>> Connected! Other user(s): {}", &list_str)).expect("Pass msg1");
+    send_files(sender.clone());
     out_msg
 }
 
@@ -72,32 +199,119 @@ fn handle_add_user(addr: &SocketAddr) -> connection_utils::Receiver {
 }
 
 fn handle_removed_user(addr: &SocketAddr) {
+    moderation::forget(addr);
+    AWAY.lock().expect("away").remove(addr);
     let mut clients = PEERS.lock().expect("State lock 3");
     if let Some((_, Some(disconected_name))) = clients.remove(&addr) {
         let list_str = format!(">>> {} left. User(s):{}", disconected_name, &list_clients(&clients, &addr));
+        events::publish(ChatEvent::Leave { name: disconected_name });
         for (_, (sender, name)) in &mut (*clients) {
             if *name != None {
                 connection_utils::pass_line(sender, list_str.clone()).expect("Pass msg3");
             }
         }
         print(&list_str);
+        let members = members_line(&clients);
+        send_to_named(&mut clients, &members);
     }
     else { print(&format!(">>> {} disconnected", &addr)); }
 }
 
+/// Disconnects every named user matching `predicate`, returns how many were kicked.
+pub fn kick_matching<F: Fn(&SocketAddr, &str) -> bool>(predicate: F) -> usize {
+    let addrs: Vec<SocketAddr> = {
+        let mut clients = PEERS.lock().expect("State lock kick");
+        clients.iter_mut()
+            .filter(|(addr, (_, name))| name.as_ref().map(|name| predicate(addr, name)).unwrap_or(false))
+            .map(|(addr, (sender, _))| {
+                let _ = connection_utils::pass_line(sender, ">>> You were kicked by the server".to_string());
+                *addr
+            })
+            .collect()
+    };
+    for addr in &addrs {
+        handle_removed_user(addr);
+    }
+    addrs.len()
+}
+
+fn find_client<'a>(clients: &'a mut Clients, name: &str) -> Option<&'a mut connection_utils::Sender> {
+    clients.values_mut()
+        .find(|(_, client_name)| client_name.as_ref().map(|n| n.as_str()) == Some(name))
+        .map(|(sender, _)| sender)
+}
+
+fn handle_control_msg(clients: &mut Clients, addr: &SocketAddr, name: String, msg: &str) {
+    match connection_utils::parse_control(msg, 3) {
+        Some(ref fields) if fields.len() == 3 && fields[0] == "TO" => {
+            let forwarded = connection_utils::control_line(&["FROM", &name, fields[2]]);
+            if let Some(sender) = find_client(clients, fields[1]) {
+                let _ = connection_utils::pass_line(sender, forwarded);
+                return;
+            }
+            if let Some((sender, _)) = clients.get_mut(addr) {
+                let _ = connection_utils::pass_line(sender, format!(">>> No user named {}", fields[1]));
+            }
+        },
+        Some(ref fields) if fields.len() == 2 && fields[0] == "AWAY" => {
+            let away = fields[1] == "on";
+            let changed = if away { AWAY.lock().expect("away").insert(*addr) } else { AWAY.lock().expect("away").remove(addr) };
+            if changed {
+                print(&format!(">>> {} is {}", name, if away { "away" } else { "back" }));
+                let members = members_line(clients);
+                send_to_named(clients, &members);
+            }
+        },
+        _ => print(&format!(">>> Unknown control message from {}", name)),
+    }
+}
+
 fn handle_receive_msg(connection: &TextConnection, msg : String){
     let addr = connection.lines.socket.peer_addr().expect("Socket address 1");
+    handle_line(addr, msg);
+}
+
+fn handle_line(addr: SocketAddr, msg : String){
     let mut mg = PEERS.lock().expect("State lock 1");
     let out_msg: String;
-    if let (_, Some(name)) = mg.get(&addr).expect("Known address") {
+    let client_name = match mg.get(&addr) {
+        Some((_, name)) => name.clone(),
+        None => return, // kicked, the connection is closing
+    };
+    if let Some(name) = client_name {
+        if msg.starts_with(connection_utils::CONTROL_PREFIX) {
+            handle_control_msg(&mut mg, &addr, name, &msg);
+            return;
+        }
+        if moderation::is_command(&addr, &msg) {
+            drop(mg);
+            let reply = moderation::handle_client_command(&addr, &name, &msg);
+            if let Some((sender, _)) = PEERS.lock().expect("State lock reply").get_mut(&addr) {
+                let _ = connection_utils::pass_line(sender, reply);
+            }
+            return;
+        }
+        if moderation::is_muted(&name) {
+            let (sender, _) = mg.get_mut(&addr).expect("Known address");
+            let _ = connection_utils::pass_line(sender, ">>> You are muted, message dropped".to_string());
+            return;
+        }
         out_msg = format!("{}: {}", &name, &msg);
         push_history(out_msg.clone());
+        events::publish(ChatEvent::Message { from: name.clone(), text: connection_utils::unescape_line(&msg) });
+    } else if moderation::is_banned_nick(&msg) {
+        let (mut sender, _) = mg.remove(&addr).expect("Known address");
+        let _ = connection_utils::pass_line(&mut sender, ">>> You are banned".to_string());
+        print(&format!(">>> Banned user {} {:?} refused", &msg, &addr));
+        return;
     } else {
        out_msg = handle_new_named_user(&mut mg, &addr, msg);
+       let members = members_line(&mg);
+       send_to_named(&mut mg, &members);
     }
     for (addrit, (sender, name)) in &mut (*mg) {
         if *addrit != addr && *name != None {
-            connection_utils::pass_line(sender, out_msg.clone()).expect("Pass msg2");
+            let _ = connection_utils::pass_line(sender, out_msg.clone());
         }
     }
     print(&out_msg);
@@ -105,10 +319,24 @@ fn handle_receive_msg(connection: &TextConnection, msg : String){
 
 fn handle_text_connection(socket :TcpStream) -> Result<(), std::io::Error> {
     let addr = socket.peer_addr().expect("Socket addr 0");
+    if moderation::is_banned_ip(&addr.ip()) {
+        let (mut sender, receiver) = futures::sync::mpsc::channel(connection_utils::CHANNEL_BUFF_SIZE);
+        let _ = connection_utils::pass_line(&mut sender, ">>> You are banned".to_string());
+        print(&format!(">>> Banned address {:?} refused", &addr));
+        let con = TextConnection::new(receiver, socket, Box::new(|_, _| {}))
+            .map_err(move |e| { print(&format!(">>> transfer error = {:?}", e)); });
+        tokio::spawn(con);
+        return Ok(());
+    }
     let receiver = handle_add_user(&addr);
     let con = TextConnection::new(receiver, socket, Box::new(handle_receive_msg))
-        .and_then(move |_|{ handle_removed_user(&addr); Ok(()) })
-        .map_err(move |e| { print(&format!(">>> transfer error = {:?}", e)); });
+        .with_max_line_length(config::CONFIG.max_line_length())
+        .with_rate_limit(config::CONFIG.rate_limit.to_limit())
+        .then(move |result| {
+            if let Err(e) = result { print(&format!(">>> {:?} transfer error = {:?}", &addr, e)); }
+            handle_removed_user(&addr);
+            Ok(())
+        });
     tokio::spawn(con);
     Ok(())
 }
@@ -127,28 +355,52 @@ fn spawn_save_body_to_file(body: hyper::Body, file_path: std::path::PathBuf){
             if let Err(e) = file.write_all(&chunks) { return Err(e); }
             if let Err(e) = file.sync_all()  { return Err(e); }
 
-            let mut clients = PEERS.lock().expect("clients");
-            let msg = format!(">>> Server received file: {}", file_path.file_name().unwrap().to_str().unwrap());            
-            for (_, (sender, name)) in &mut (*clients) {
-                if *name != None {
-                    connection_utils::pass_line(sender, msg.clone()).expect("Pass msg");
-                }
+            let file_name = file_path.file_name().unwrap().to_str().unwrap().to_string();
+            broadcast_line(format!(">>> Server received file: {}", &file_name));
+            let lines = files_lines();
+            let mut clients = PEERS.lock().expect("State lock files");
+            for line in &lines {
+                send_to_named(&mut clients, line);
             }
-            print(&msg);
-            push_history(msg);
+            events::publish(ChatEvent::File { name: file_name });
             Ok(())
         }).map_err(|err| { print(&format!("save_body_to_file error: {:?}", err)); });
     tokio::spawn(task);
 }
 
-fn handle_file_server_request(request: hyper::Request<hyper::Body>) -> hyper::Response<hyper::Body> {
+fn file_etag(metadata: &std::fs::Metadata, modified: std::time::SystemTime) -> String {
+    let modified_secs = modified.duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    format!("\"{:x}-{:x}\"", modified_secs, metadata.len())
+}
+
+fn content_disposition(filename: &str) -> String {
+    let ascii_name: String = filename.chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", ascii_name, connection_utils::percent_encode(filename))
+}
+
+fn is_not_modified(headers: &hyper::HeaderMap, etag: &str, modified: std::time::SystemTime) -> bool {
+    if let Some(if_none_match) = headers.get(hyper::header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        return if_none_match.split(',').map(|tag| tag.trim()).any(|tag| tag == etag || tag == "*");
+    }
+    let since = headers.get(hyper::header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+    match (since, modified.duration_since(std::time::UNIX_EPOCH)) {
+        (Some(since), Ok(modified)) => since.duration_since(std::time::UNIX_EPOCH).map(|s| s.as_secs() >= modified.as_secs()).unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn handle_file_request(request: hyper::Request<hyper::Body>) -> hyper::Response<hyper::Body> {
     let method = request.method();
-    if method != hyper::Method::PUT && method != hyper::Method::GET {
+    if method != hyper::Method::PUT && method != hyper::Method::GET && method != hyper::Method::HEAD {
         print(&format!(">>> METHOD_NOT_ALLOWED: {:?}", method));
         return hyper::Response::builder().status(hyper::http::StatusCode::METHOD_NOT_ALLOWED)
-            .header("Allow", "PUT, GET").body(hyper::Body::empty()).unwrap();
+            .header("Allow", "PUT, GET, HEAD").body(hyper::Body::empty()).unwrap();
     }
-    let path_uri = request.uri().path().to_owned();
+    let path_uri = connection_utils::percent_decode(request.uri().path());
     let file_name = std::path::Path::new(&path_uri);
     if None == file_name.file_stem() {
         print(&format!(">>> wrong request path: {:?}", file_name));
@@ -167,19 +419,58 @@ fn handle_file_server_request(request: hyper::Request<hyper::Body>) -> hyper::Re
         return hyper::Response::builder().status(status).body(hyper::Body::empty()).unwrap()
     } 
 
-    /////// GET
-    std::assert_eq!(method, hyper::Method::GET);
+    /////// GET, HEAD
     if !already_exist {
         print(&format!(">>> wrong request path: {:?}", file_name));
         return hyper::Response::builder().status(hyper::http::StatusCode::NOT_FOUND).body(hyper::Body::empty()).unwrap();
     }
+    let metadata = match std::fs::metadata(&file_path) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            print(&format!(">>> Reading file error: {:?}", e));
+            return hyper::Response::builder().status(hyper::http::StatusCode::INTERNAL_SERVER_ERROR).body(hyper::Body::empty()).unwrap();
+        }
+    };
+    let modified = metadata.modified().unwrap_or(std::time::UNIX_EPOCH);
+    let etag = file_etag(&metadata, modified);
+    let mut builder = hyper::Response::builder();
+    builder.header(hyper::header::ETAG, etag.as_str())
+        .header(hyper::header::LAST_MODIFIED, httpdate::fmt_http_date(modified).as_str());
+    if is_not_modified(request.headers(), &etag, modified) {
+        return builder.status(hyper::http::StatusCode::NOT_MODIFIED).body(hyper::Body::empty()).unwrap();
+    }
+    builder.header(hyper::header::CONTENT_TYPE, mime_guess::from_path(&file_path).first_or_octet_stream().as_ref())
+        .header(hyper::header::CONTENT_DISPOSITION, content_disposition(&filename_str.to_string_lossy()).as_str())
+        .header(hyper::header::CONTENT_LENGTH, metadata.len());
+    if method == hyper::Method::HEAD {
+        return builder.body(hyper::Body::empty()).unwrap();
+    }
     let file_content = connection_utils::read_file_blocking(&file_path);
     if let Err(e) = file_content {
         print(&format!(">>> Reading file error: {:?}", e));
         return hyper::Response::builder().status(hyper::http::StatusCode::INTERNAL_SERVER_ERROR).body(hyper::Body::empty()).unwrap();
     }
     print(&format!(">>> File send {:?}", &file_path));
-    hyper::Response::new(file_content.unwrap().into())
+    builder.body(file_content.unwrap().into()).unwrap()
+}
+
+fn handle_file_server_request(request: hyper::Request<hyper::Body>, remote_addr: SocketAddr) -> ResponseFuture {
+    if request.uri().path() == ws::WS_PATH {
+        return ws::handle_ws_request(request, remote_addr);
+    }
+    if request.uri().path().starts_with(relay::RELAY_PATH) {
+        return relay::handle_relay_request(request);
+    }
+    if request.uri().path() == events::EVENTS_PATH {
+        return events::handle_events_request(request);
+    }
+    if request.uri().path().starts_with(api::API_PATH) {
+        return api::handle_api_request(request);
+    }
+    if request.uri().path() == "/" || request.uri().path().starts_with(web::WEB_PATH) {
+        return web::handle_web_request(request, remote_addr);
+    }
+    Box::new(future::ok(handle_file_request(request)))
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////
@@ -187,6 +478,8 @@ fn handle_file_server_request(request: hyper::Request<hyper::Body>) -> hyper::Re
 pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     let version = env!("CARGO_PKG_VERSION");
     print(&format!(">>> Connection version: {}", version));
+    lazy_static::initialize(&config::CONFIG);
+    moderation::init();
     let my_ip: IpAddr = connection_utils::list_ip().expect("a valid ip");
     let mut rt = Builder::new().build().unwrap();
 
@@ -203,11 +496,25 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
         let listen_addr = SocketAddr::new(my_ip, connection_utils::SERVER_PORT_FILE);
         print(&format!(">>> File server on {:?}", &listen_addr));
         let file_server = hyper::Server::bind(&listen_addr)
-            .serve(|| { hyper::service::service_fn_ok(handle_file_server_request) })
+            .serve(hyper::service::make_service_fn(|socket: &hyper::server::conn::AddrStream| {
+                let remote_addr = socket.remote_addr();
+                hyper::service::service_fn(move |request| handle_file_server_request(request, remote_addr))
+            }))
             .map_err(|err| { print(&format!(">>> File server error {:?}", err)); });
         rt.spawn(file_server);
     }
 
+    if let Some(irc_port) = config::CONFIG.irc_port {
+        let listen_addr = SocketAddr::new(my_ip, irc_port);
+        print(&format!(">>> IRC gateway on: {:?}, channel {}", &listen_addr, irc::IRC_CHANNEL));
+        let irc_server = TcpListener::bind(&listen_addr)?.incoming()
+            .for_each(irc::handle_irc_connection)
+            .map_err(move |err| { print(&format!(">>> IRC server error = {:?}", err)); });
+        rt.spawn(irc_server);
+    }
+
+    admin::admin_job(&mut rt);
+
     rt.shutdown_on_idle().wait().unwrap();
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,174 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::string::String;
+use crate::print;
+
+pub const BANS_FILE: &str = "ConnectionServer.bans";
+pub const MODERATION_HELP: &str = ">>> Moderation: /kick <nick>, /ban <nick|ip>, /unban <nick|ip>, /bans, /mute <nick>, /unmute <nick>";
+
+struct Moderation {
+    operators: HashSet<SocketAddr>,
+    muted: HashSet<String>,
+    bans: Vec<String>,
+}
+
+fn bans_path() -> PathBuf {
+    let mut file_path = dirs::data_local_dir().unwrap();
+    file_path.push(BANS_FILE);
+    file_path
+}
+
+fn load_bans() -> Vec<String> {
+    std::fs::read_to_string(bans_path())
+        .map(|content| content.lines().map(|line| line.trim().to_string()).filter(|line| !line.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+fn save_bans(bans: &[String]) {
+    let mut content = bans.join("\n");
+    content.push('\n');
+    if let Err(e) = std::fs::write(bans_path(), content) {
+        print(&format!(">>> Cannot save ban list: {}", e));
+    }
+}
+
+lazy_static! {
+    static ref MODERATION: Mutex<Moderation> = Mutex::new(Moderation {
+        operators: HashSet::new(),
+        muted: HashSet::new(),
+        bans: load_bans(),
+    });
+}
+
+pub fn init() {
+    let count = MODERATION.lock().expect("moderation").bans.len();
+    if count > 0 {
+        print(&format!(">>> Ban list: {} entries", count));
+    }
+}
+
+pub fn is_banned_ip(ip: &IpAddr) -> bool {
+    let ip = ip.to_string();
+    MODERATION.lock().expect("moderation").bans.contains(&ip)
+}
+
+pub fn is_banned_nick(nick: &str) -> bool {
+    MODERATION.lock().expect("moderation").bans.iter().any(|ban| ban == nick)
+}
+
+pub fn is_muted(nick: &str) -> bool {
+    MODERATION.lock().expect("moderation").muted.contains(nick)
+}
+
+/// For messages that do not come through `handle_line`, web and API posts use the bare name.
+pub fn may_post(nick: &str) -> bool {
+    !is_banned_nick(nick) && !is_muted(nick)
+}
+
+pub fn is_operator(addr: &SocketAddr) -> bool {
+    MODERATION.lock().expect("moderation").operators.contains(addr)
+}
+
+pub fn forget(addr: &SocketAddr) {
+    MODERATION.lock().expect("moderation").operators.remove(addr);
+}
+
+/// `/op` is never chat, the other moderation commands only when they come from an operator,
+/// so anyone else can still start a message with `/ban` or `/mute`.
+pub fn is_command(addr: &SocketAddr, line: &str) -> bool {
+    let command = line.split(' ').next().unwrap_or("");
+    command == "/op" || (["/kick", "/ban", "/unban", "/bans", "/mute", "/unmute"].contains(&command) && is_operator(addr))
+}
+
+fn ban(entry: &str) -> String {
+    {
+        let mut moderation = MODERATION.lock().expect("moderation");
+        if moderation.bans.iter().any(|ban| ban == entry) {
+            return format!(">>> {} is already banned", entry);
+        }
+        moderation.bans.push(entry.to_string());
+        save_bans(&moderation.bans);
+    }
+    let kicked = match entry.parse::<IpAddr>() {
+        Ok(ip) => crate::kick_matching(|addr, _| addr.ip() == ip),
+        Err(_) => crate::kick_matching(|_, name| name == entry),
+    };
+    format!(">>> Banned {}, {} user(s) disconnected", entry, kicked)
+}
+
+fn unban(entry: &str) -> String {
+    let mut moderation = MODERATION.lock().expect("moderation");
+    let count = moderation.bans.len();
+    moderation.bans.retain(|ban| ban != entry);
+    if moderation.bans.len() == count {
+        return format!(">>> {} is not banned", entry);
+    }
+    save_bans(&moderation.bans);
+    format!(">>> Unbanned {}", entry)
+}
+
+fn list_bans() -> String {
+    let moderation = MODERATION.lock().expect("moderation");
+    if moderation.bans.is_empty() {
+        return ">>> Ban list is empty".to_string();
+    }
+    format!(">>> Banned: {}", moderation.bans.join(" "))
+}
+
+fn mute(nick: &str) -> String {
+    if MODERATION.lock().expect("moderation").muted.insert(nick.to_string()) {
+        return format!(">>> Muted {}", nick);
+    }
+    format!(">>> {} is already muted", nick)
+}
+
+fn unmute(nick: &str) -> String {
+    if MODERATION.lock().expect("moderation").muted.remove(nick) {
+        return format!(">>> Unmuted {}", nick);
+    }
+    format!(">>> {} is not muted", nick)
+}
+
+/// Runs a moderation command for the server console or an operator, returns the reply.
+pub fn handle_command(line: &str) -> Option<String> {
+    let (command, argument) = match line.find(' ') {
+        Some(pos) => (&line[..pos], line[pos + 1..].trim()),
+        None => (line, ""),
+    };
+    let reply = match (command, argument) {
+        ("/kick", nick) if !nick.is_empty() => {
+            if crate::kick_matching(|_, name| name == nick) > 0 { format!(">>> Kicked {}", nick) }
+            else { format!(">>> No user named {}", nick) }
+        },
+        ("/ban", entry) if !entry.is_empty() => ban(entry),
+        ("/unban", entry) if !entry.is_empty() => unban(entry),
+        ("/bans", "") => list_bans(),
+        ("/mute", nick) if !nick.is_empty() => mute(nick),
+        ("/unmute", nick) if !nick.is_empty() => unmute(nick),
+        ("/kick", _) | ("/ban", _) | ("/unban", _) | ("/bans", _) | ("/mute", _) | ("/unmute", _) => MODERATION_HELP.to_string(),
+        _ => return None,
+    };
+    Some(reply)
+}
+
+/// Handles a moderation line typed by a chat client, `/op <password>` grants the operator role.
+pub fn handle_client_command(addr: &SocketAddr, name: &str, line: &str) -> String {
+    if let Some(password) = line.strip_prefix("/op") {
+        let password = password.trim();
+        return match crate::config::CONFIG.operator_password {
+            Some(ref expected) if !password.is_empty() && expected == password => {
+                MODERATION.lock().expect("moderation").operators.insert(*addr);
+                print(&format!(">>> {} is now an operator", name));
+                ">>> You are now an operator".to_string()
+            },
+            _ => ">>> Wrong operator password".to_string(),
+        };
+    }
+    if !is_operator(addr) {
+        return ">>> You are not an operator, use /op <password>".to_string();
+    }
+    print(&format!(">>> {} (operator): {}", name, line));
+    handle_command(line).unwrap_or_else(|| MODERATION_HELP.to_string())
+}
@@ -0,0 +1,117 @@
+extern crate tokio;
+extern crate futures;
+extern crate hyper;
+
+use tokio::prelude::*;
+use futures::sync::{mpsc, oneshot};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::string::String;
+use crate::{print, respond, ResponseFuture};
+
+pub const RELAY_PATH: &str = "/relay/";
+pub const RELAY_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// `None` after the last chunk, a downloader that never gets it sees an error rather than a short file.
+type RelaySender = mpsc::Sender<Option<hyper::Chunk>>;
+
+enum RelaySlot {
+    Downloader(RelaySender),
+    Uploader(hyper::Body, oneshot::Sender<()>),
+}
+
+lazy_static! {
+    static ref RELAYS: Mutex<HashMap<String, RelaySlot>> = Mutex::new(HashMap::new());
+}
+
+fn pipe(body: hyper::Body, sender: RelaySender) -> impl Future<Item = (), Error = ()> {
+    body.map(Some)
+        .map_err(|err| { print(&format!(">>> Relay upload error {:?}", err)); })
+        .chain(stream::once(Ok(None)))
+        .forward(sender.sink_map_err(|_| { print(">>> Relay download closed"); }))
+        .map(|_| ())
+}
+
+fn downloader_body(mut receiver: mpsc::Receiver<Option<hyper::Chunk>>) -> hyper::Body {
+    let mut finished = false;
+    hyper::Body::wrap_stream(stream::poll_fn(move || {
+        if finished {
+            return Ok(Async::Ready(None));
+        }
+        match receiver.poll() {
+            Ok(Async::Ready(Some(Some(chunk)))) => Ok(Async::Ready(Some(chunk))),
+            Ok(Async::Ready(Some(None))) => { finished = true; Ok(Async::Ready(None)) },
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "relay upload ended early")),
+        }
+    }))
+}
+
+fn expire_slot(token: String) {
+    let task = tokio::timer::Delay::new(Instant::now() + RELAY_TIMEOUT)
+        .map(move |_| {
+            if RELAYS.lock().expect("relays").remove(&token).is_some() {
+                print(&format!(">>> Relay {} expired", token));
+            }
+        })
+        .map_err(|err| { print(&format!(">>> Relay timer error {:?}", err)); });
+    tokio::spawn(task);
+}
+
+fn handle_download(token: String) -> ResponseFuture {
+    let mut relays = RELAYS.lock().expect("relays");
+    let (sender, receiver) = mpsc::channel(connection_utils::CHANNEL_BUFF_SIZE);
+    match relays.remove(&token) {
+        Some(RelaySlot::Uploader(body, done)) => {
+            print(&format!(">>> Relaying {}", token));
+            tokio::spawn(pipe(body, sender).then(move |_| { let _ = done.send(()); Ok(()) }));
+        },
+        Some(slot) => {
+            relays.insert(token, slot);
+            return Box::new(future::ok(respond(hyper::http::StatusCode::CONFLICT, hyper::Body::empty())));
+        },
+        None => {
+            relays.insert(token.clone(), RelaySlot::Downloader(sender));
+            expire_slot(token);
+        },
+    }
+    Box::new(future::ok(respond(hyper::http::StatusCode::OK, downloader_body(receiver))))
+}
+
+fn handle_upload(token: String, body: hyper::Body) -> ResponseFuture {
+    let mut relays = RELAYS.lock().expect("relays");
+    match relays.remove(&token) {
+        Some(RelaySlot::Downloader(sender)) => {
+            print(&format!(">>> Relaying {}", token));
+            Box::new(pipe(body, sender)
+                .then(|_| Ok(respond(hyper::http::StatusCode::OK, hyper::Body::empty()))))
+        },
+        Some(slot) => {
+            relays.insert(token, slot);
+            Box::new(future::ok(respond(hyper::http::StatusCode::CONFLICT, hyper::Body::empty())))
+        },
+        None => {
+            let (done, finished) = oneshot::channel();
+            relays.insert(token.clone(), RelaySlot::Uploader(body, done));
+            expire_slot(token);
+            Box::new(finished.then(|result| {
+                let status = if result.is_ok() { hyper::http::StatusCode::OK } else { hyper::http::StatusCode::GONE };
+                Ok(respond(status, hyper::Body::empty()))
+            }))
+        },
+    }
+}
+
+pub fn handle_relay_request(request: hyper::Request<hyper::Body>) -> ResponseFuture {
+    let token = request.uri().path()[RELAY_PATH.len()..].to_string();
+    if token.is_empty() || token.contains('/') {
+        return Box::new(future::ok(respond(hyper::http::StatusCode::BAD_REQUEST, hyper::Body::empty())));
+    }
+    match *request.method() {
+        hyper::Method::GET => handle_download(token),
+        hyper::Method::PUT => handle_upload(token, request.into_body()),
+        _ => Box::new(future::ok(hyper::Response::builder().status(hyper::http::StatusCode::METHOD_NOT_ALLOWED)
+            .header("Allow", "PUT, GET").body(hyper::Body::empty()).unwrap())),
+    }
+}
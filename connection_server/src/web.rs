@@ -0,0 +1,117 @@
+extern crate tokio;
+extern crate futures;
+extern crate hyper;
+
+use tokio::prelude::*;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::string::String;
+use crate::{print, respond, ResponseFuture};
+
+pub const WEB_PATH: &str = "/ui/";
+pub const MAX_WEB_BODY: usize = 16 * 1024;
+pub const MAX_NAME_LENGTH: usize = 32;
+const INDEX_HTML: &str = include_str!("web/index.html");
+
+lazy_static! {
+    static ref POST_LIMITS: Mutex<HashMap<IpAddr, connection_utils::RateLimiter>> = Mutex::new(HashMap::new());
+}
+
+pub fn json_response(status: hyper::http::StatusCode, value: serde_json::Value) -> hyper::Response<hyper::Body> {
+    hyper::Response::builder().status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json; charset=utf-8")
+        .body(value.to_string().into()).unwrap()
+}
+
+pub fn query_param<'a>(query: Option<&'a str>, key: &str) -> Option<&'a str> {
+    query?.split('&')
+        .map(|pair| { let mut kv = pair.splitn(2, '='); (kv.next().unwrap_or(""), kv.next().unwrap_or("")) })
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+pub fn read_json_body(body: hyper::Body) -> impl Future<Item = Option<serde_json::Value>, Error = hyper::Error> {
+    body.fold(Some(Vec::new()), |content, chunk| {
+            future::ok::<_, hyper::Error>(content.and_then(|mut content: Vec<u8>| {
+                if content.len() + chunk.len() > MAX_WEB_BODY { return None; }
+                content.extend(&chunk[..]);
+                Some(content)
+            }))
+        })
+        .map(|content| content.and_then(|content| serde_json::from_slice(&content).ok()))
+}
+
+pub fn single_line(text: &str) -> String {
+    text.chars().map(|c| if c.is_control() { ' ' } else { c }).collect::<String>().trim().to_string()
+}
+
+/// Keeps line breaks of a posted message and returns it escaped for the chat.
+pub fn chat_text(text: &str) -> String {
+    let text: String = text.chars().filter(|c| *c == '\n' || !c.is_control()).collect();
+    connection_utils::escape_line(text.trim())
+}
+
+/// A post cannot be slowed down like a connection, past the bucket of its address it is refused.
+fn may_post_from(ip: IpAddr, text: &str) -> bool {
+    let mut limits = POST_LIMITS.lock().expect("post limits");
+    limits.retain(|_, limiter| !limiter.is_rested());
+    limits.entry(ip)
+        .or_insert_with(|| connection_utils::RateLimiter::new(crate::config::CONFIG.rate_limit.to_limit()))
+        .try_take(text.len())
+}
+
+/////////////////////////////////////////////////////////////////
+
+fn list_messages(query: Option<&str>) -> hyper::Response<hyper::Body> {
+    let since = query_param(query, "since").and_then(|v| v.parse().ok()).unwrap_or(0);
+    let messages: Vec<serde_json::Value> = crate::HISTORY.lock().expect("history").since(since).into_iter()
+        .map(|(id, text)| json!({ "id": id, "text": connection_utils::unescape_line(&text) }))
+        .collect();
+    json_response(hyper::http::StatusCode::OK, json!({ "messages": messages }))
+}
+
+fn list_files() -> hyper::Response<hyper::Body> {
+    let files: Vec<serde_json::Value> = crate::stored_files().unwrap_or_default().into_iter()
+        .map(|(name, size)| json!({ "name": name, "size": size }))
+        .collect();
+    json_response(hyper::http::StatusCode::OK, json!({ "files": files }))
+}
+
+fn post_message(body: hyper::Body, remote_addr: SocketAddr) -> ResponseFuture {
+    Box::new(read_json_body(body).map(move |value| {
+        let field = |key| value.as_ref().and_then(|v| v.get(key)).and_then(|v| v.as_str()).unwrap_or_default();
+        let (name, text) = (single_line(field("name")), chat_text(field("text")));
+        if name.is_empty() || text.is_empty() || name.chars().count() > MAX_NAME_LENGTH || name.contains(' ') {
+            return respond(hyper::http::StatusCode::BAD_REQUEST, hyper::Body::empty());
+        }
+        if !crate::moderation::may_post(&name) {
+            return respond(hyper::http::StatusCode::FORBIDDEN, hyper::Body::empty());
+        }
+        if !may_post_from(remote_addr.ip(), &text) {
+            return respond(hyper::http::StatusCode::TOO_MANY_REQUESTS, hyper::Body::empty());
+        }
+        let from = format!("{}@web", name);
+        crate::broadcast_line(format!("{}: {}", &from, &text));
+        crate::events::publish(crate::events::ChatEvent::Message { from, text: connection_utils::unescape_line(&text) });
+        respond(hyper::http::StatusCode::NO_CONTENT, hyper::Body::empty())
+    }))
+}
+
+pub fn handle_web_request(request: hyper::Request<hyper::Body>, remote_addr: SocketAddr) -> ResponseFuture {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let response = match (method, path.as_str()) {
+        (hyper::Method::GET, "/") => hyper::Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(INDEX_HTML.into()).unwrap(),
+        (hyper::Method::GET, "/ui/messages") => list_messages(request.uri().query()),
+        (hyper::Method::POST, "/ui/messages") => return post_message(request.into_body(), remote_addr),
+        (hyper::Method::GET, "/ui/files") => list_files(),
+        (method, path) => {
+            print(&format!(">>> Unknown web request: {} {}", method, path));
+            respond(hyper::http::StatusCode::NOT_FOUND, hyper::Body::empty())
+        },
+    };
+    Box::new(future::ok(response))
+}
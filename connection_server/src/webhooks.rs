@@ -0,0 +1,132 @@
+extern crate tokio;
+extern crate futures;
+extern crate hyper;
+extern crate regex;
+
+use tokio::prelude::*;
+use futures::future::Loop;
+use std::time::{Duration, Instant};
+use std::string::String;
+use crate::print;
+use crate::config::{WebhookConfig, CONFIG};
+use crate::events::ChatEvent;
+
+pub const WEBHOOK_ATTEMPTS: u32 = 4;
+pub const WEBHOOK_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+struct Webhook {
+    config: &'static WebhookConfig,
+    uri: hyper::Uri,
+    pattern: Option<regex::Regex>,
+}
+
+lazy_static! {
+    static ref WEBHOOKS: Vec<Webhook> = CONFIG.webhooks.iter().filter_map(|config| {
+        let uri = match config.url.parse() {
+            Ok(uri) => uri,
+            Err(e) => { print(&format!(">>> Wrong webhook url {}: {}", config.url, e)); return None; }
+        };
+        let pattern = match config.pattern.as_ref().map(|pattern| regex::Regex::new(pattern)).transpose() {
+            Ok(pattern) => pattern,
+            Err(e) => { print(&format!(">>> Wrong webhook pattern {}: {}", config.url, e)); return None; }
+        };
+        Some(Webhook { config, uri, pattern })
+    }).collect();
+}
+
+impl Webhook {
+    fn accepts(&self, event: &ChatEvent) -> bool {
+        if !self.config.events.is_empty() && !self.config.events.iter().any(|kind| kind == event.kind()) {
+            return false;
+        }
+        match (event, &self.pattern) {
+            (ChatEvent::Message { text, .. }, Some(pattern)) => pattern.is_match(text),
+            _ => true,
+        }
+    }
+}
+
+type LoopFuture = Box<dyn Future<Item = Loop<(), u32>, Error = ()> + Send>;
+
+fn post_with_retry(uri: hyper::Uri, body: String) -> impl Future<Item = (), Error = ()> {
+    future::loop_fn(0u32, move |attempt| {
+        let request = hyper::Request::post(uri.clone())
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(body.clone().into()).expect("request builder");
+        let uri = uri.clone();
+        hyper::Client::new().request(request).then(move |result| -> LoopFuture {
+            let error = match result {
+                Ok(ref res) if res.status().is_success() => return Box::new(future::ok(Loop::Break(()))),
+                Ok(res) => format!("status {}", res.status()),
+                Err(e) => e.to_string(),
+            };
+            if attempt + 1 >= WEBHOOK_ATTEMPTS {
+                print(&format!(">>> Webhook {} failed ({}), giving up", uri, error));
+                return Box::new(future::ok(Loop::Break(())));
+            }
+            let delay = WEBHOOK_RETRY_DELAY * 2u32.pow(attempt);
+            print(&format!(">>> Webhook {} failed ({}), retrying in {:?}", uri, error, delay));
+            Box::new(tokio::timer::Delay::new(Instant::now() + delay)
+                .map(move |_| Loop::Continue(attempt + 1))
+                .map_err(|err| { print(&format!(">>> Webhook timer error {:?}", err)); }))
+        })
+    })
+}
+
+pub fn dispatch(event: &ChatEvent) {
+    let mut body = None;
+    for webhook in WEBHOOKS.iter().filter(|webhook| webhook.accepts(event)) {
+        let body = body.get_or_insert_with(|| event.to_json().to_string()).clone();
+        tokio::spawn(post_with_retry(webhook.uri.clone(), body));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn webhook(events: &[&str], pattern: Option<&str>) -> Webhook {
+        let config: &'static WebhookConfig = Box::leak(Box::new(WebhookConfig {
+            url: "http://127.0.0.1/hook".to_string(),
+            events: events.iter().map(|event| event.to_string()).collect(),
+            pattern: pattern.map(|pattern| pattern.to_string()),
+        }));
+        Webhook { config, uri: config.url.parse().unwrap(), pattern: pattern.map(|pattern| regex::Regex::new(pattern).unwrap()) }
+    }
+
+    #[test]
+    fn filters_by_kind_and_pattern() {
+        let hook = webhook(&["message"], Some("deploy"));
+        assert!(hook.accepts(&ChatEvent::Message { from: "bob".to_string(), text: "deploy done".to_string() }));
+        assert!(!hook.accepts(&ChatEvent::Message { from: "bob".to_string(), text: "hello".to_string() }));
+        assert!(!hook.accepts(&ChatEvent::Join { name: "bob".to_string() }));
+        assert!(webhook(&[], None).accepts(&ChatEvent::File { name: "a.txt".to_string() }));
+    }
+
+    /// A local receiver that fails the first delivery, the retry has to bring the same body again.
+    #[test]
+    fn retries_until_the_receiver_accepts() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+            let log = log.clone();
+            hyper::service::service_fn(move |request: hyper::Request<hyper::Body>| {
+                let log = log.clone();
+                request.into_body().concat2().map(move |body| {
+                    let mut log = log.lock().unwrap();
+                    log.push(String::from_utf8_lossy(&body).to_string());
+                    let status = if log.len() == 1 { hyper::StatusCode::INTERNAL_SERVER_ERROR } else { hyper::StatusCode::OK };
+                    hyper::Response::builder().status(status).body(hyper::Body::empty()).unwrap()
+                })
+            })
+        });
+        let uri = format!("http://{}/hook", server.local_addr()).parse().unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.spawn(server.map_err(|_| ()));
+
+        let body = ChatEvent::Join { name: "bob".to_string() }.to_json().to_string();
+        rt.block_on(post_with_retry(uri, body.clone())).unwrap();
+        assert_eq!(*received.lock().unwrap(), vec![body.clone(), body]);
+    }
+}
@@ -0,0 +1,329 @@
+extern crate tokio;
+extern crate futures;
+extern crate bytes;
+extern crate hyper;
+
+use tokio::prelude::*;
+use tokio::codec::{Decoder, Encoder, Framed};
+use bytes::{BufMut, BytesMut};
+use futures::sync::{mpsc, oneshot};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::string::String;
+use crate::{print, respond, ResponseFuture};
+
+pub const WS_PATH: &str = "/ws";
+pub const MAX_WS_MESSAGE: usize = 64 * 1024;
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    Text(String),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close,
+}
+
+#[derive(Default)]
+pub struct WsCodec {
+    fragments: Vec<u8>,
+    fragment_opcode: Option<u8>,
+}
+
+fn protocol_error(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+impl Decoder for WsCodec {
+    type Item = Frame;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, std::io::Error> {
+        loop {
+            if src.len() < 2 {
+                return Ok(None);
+            }
+            let fin = src[0] & 0x80 != 0;
+            let opcode = src[0] & 0x0F;
+            if src[1] & 0x80 == 0 {
+                return Err(protocol_error("unmasked client frame"));
+            }
+            let (length, mut offset) = match src[1] & 0x7F {
+                126 if src.len() >= 4 => ((u64::from(src[2]) << 8) | u64::from(src[3]), 4),
+                127 if src.len() >= 10 => (src[2..10].iter().fold(0u64, |len, b| (len << 8) | u64::from(*b)), 10),
+                126 | 127 => return Ok(None),
+                length => (u64::from(length), 2),
+            };
+            // compared before any arithmetic, the client picks all 64 bits of the length
+            if length > MAX_WS_MESSAGE.saturating_sub(self.fragments.len()) as u64 {
+                return Err(protocol_error("message too long"));
+            }
+            let length = length as usize;
+            if src.len() < offset + 4 + length {
+                return Ok(None);
+            }
+            let mut mask = [0u8; 4];
+            mask.copy_from_slice(&src[offset..offset + 4]);
+            offset += 4;
+            let _ = src.split_to(offset);
+            let mut payload = src.split_to(length).to_vec();
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+
+            match opcode {
+                OPCODE_CLOSE => return Ok(Some(Frame::Close)),
+                OPCODE_PING => return Ok(Some(Frame::Ping(payload))),
+                OPCODE_PONG => return Ok(Some(Frame::Pong(payload))),
+                OPCODE_TEXT | OPCODE_BINARY if self.fragment_opcode.is_none() => self.fragment_opcode = Some(opcode),
+                OPCODE_CONTINUATION if self.fragment_opcode.is_some() => {},
+                _ => return Err(protocol_error("unexpected opcode")),
+            }
+            self.fragments.extend(payload);
+            if !fin {
+                continue;
+            }
+            let message = std::mem::take(&mut self.fragments);
+            if self.fragment_opcode.take() == Some(OPCODE_TEXT) {
+                let text = String::from_utf8(message).map_err(|_| protocol_error("invalid utf-8"))?;
+                return Ok(Some(Frame::Text(text)));
+            }
+        }
+    }
+}
+
+impl Encoder for WsCodec {
+    type Item = Frame;
+    type Error = std::io::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), std::io::Error> {
+        let (opcode, payload) = match frame {
+            Frame::Text(text) => (OPCODE_TEXT, text.into_bytes()),
+            Frame::Ping(payload) => (OPCODE_PING, payload),
+            Frame::Pong(payload) => (OPCODE_PONG, payload),
+            Frame::Close => (OPCODE_CLOSE, Vec::new()),
+        };
+        dst.reserve(payload.len() + 10);
+        dst.put_u8(0x80 | opcode);
+        if payload.len() < 126 {
+            dst.put_u8(payload.len() as u8);
+        } else if payload.len() <= 0xFFFF {
+            dst.put_u8(126);
+            dst.put_u16_be(payload.len() as u16);
+        } else {
+            dst.put_u8(127);
+            dst.put_u64_be(payload.len() as u64);
+        }
+        dst.put(payload);
+        Ok(())
+    }
+}
+
+/////////////////////////////////////////////////////////////////
+
+fn serve_websocket(upgraded: hyper::upgrade::Upgraded, addr: SocketAddr) {
+    let (sink, stream) = Framed::new(upgraded, WsCodec::default()).split();
+    let receiver = crate::handle_add_user(&addr);
+    let (mut control_sender, control_receiver) = mpsc::channel(connection_utils::CHANNEL_BUFF_SIZE);
+    // the chat drops the receiver when it removes the user, e.g. on a kick, the socket closes with it
+    let (removed_sender, removed) = oneshot::channel::<()>();
+    let mut removed_sender = Some(removed_sender);
+    let closing = Arc::new(AtomicBool::new(false));
+    let close_sent = closing.clone();
+
+    let outgoing = receiver
+        .filter(|line| !line.starts_with(connection_utils::CONTROL_PREFIX.to_string().as_bytes()))
+        .map(Some)
+        .chain(stream::once(Ok(None)))
+        .filter_map(move |line| match line {
+            Some(line) => {
+                let line = connection_utils::bytes_to_str(&line);
+                Some(Frame::Text(connection_utils::unescape_line(line.trim_end_matches("\r\n"))))
+            },
+            None => {
+                if let Some(removed_sender) = removed_sender.take() {
+                    let _ = removed_sender.send(());
+                }
+                if closing.load(Ordering::SeqCst) { None } else { Some(Frame::Close) }
+            },
+        })
+        .select(control_receiver)
+        .map_err(|_| protocol_error("channel closed"))
+        .forward(sink)
+        .map(|_| ())
+        .map_err(|err| { print(&format!(">>> WebSocket send error {:?}", err)); });
+    tokio::spawn(outgoing);
+
+    // the same token bucket as text connections, the next frame waits while the socket is slowed down
+    let limiter = Arc::new(Mutex::new(connection_utils::RateLimiter::new(crate::config::CONFIG.rate_limit.to_limit())));
+    let throttle = limiter.clone();
+    let incoming = stream
+        .and_then(move |frame| {
+            let throttle = throttle.clone();
+            future::poll_fn(move || throttle.lock().expect("ws limiter").poll_ready()).map(|_| frame)
+        })
+        .for_each(move |frame| {
+            match frame {
+                Frame::Text(text) => {
+                    let text = text.trim();
+                    if text.is_empty() {
+                        return Ok(());
+                    }
+                    let line = connection_utils::escape_line(text);
+                    let max_line = crate::config::CONFIG.max_line_length();
+                    if line.len() > max_line {
+                        let _ = control_sender.try_send(Frame::Text(format!(">>> Message too long, the limit is {} bytes, disconnected", max_line)));
+                        close_sent.store(true, Ordering::SeqCst);
+                        let _ = control_sender.try_send(Frame::Close);
+                        return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "line too long"));
+                    }
+                    match limiter.lock().expect("ws limiter").take(line.len()) {
+                        connection_utils::RateVerdict::Allow => {},
+                        connection_utils::RateVerdict::Warn => {
+                            let _ = control_sender.try_send(Frame::Text(connection_utils::FLOOD_WARNING.to_string()));
+                        },
+                        connection_utils::RateVerdict::Disconnect => {
+                            let _ = control_sender.try_send(Frame::Text(connection_utils::FLOOD_DISCONNECT.to_string()));
+                            close_sent.store(true, Ordering::SeqCst);
+                            let _ = control_sender.try_send(Frame::Close);
+                            return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "flood limit exceeded"));
+                        },
+                    }
+                    crate::handle_line(addr, line);
+                },
+                Frame::Ping(payload) => { let _ = control_sender.try_send(Frame::Pong(payload)); },
+                Frame::Pong(_) => {},
+                Frame::Close => {
+                    close_sent.store(true, Ordering::SeqCst);
+                    let _ = control_sender.try_send(Frame::Close);
+                    return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "closed by peer"));
+                },
+            }
+            Ok(())
+        })
+        .select(removed.then(|_| Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "removed from chat"))))
+        .map(|_| ())
+        .map_err(|(err, _)| err)
+        .then(move |result| {
+            match result {
+                Err(ref err) if err.kind() == std::io::ErrorKind::ConnectionAborted => {},
+                Err(err) => print(&format!(">>> WebSocket error = {:?}", err)),
+                Ok(_) => {},
+            }
+            crate::handle_removed_user(&addr);
+            Ok(())
+        });
+    tokio::spawn(incoming);
+}
+
+fn accept_key(key: &[u8]) -> String {
+    let mut data = key.to_vec();
+    data.extend(WS_GUID.as_bytes());
+    base64::encode(&sha1::Sha1::from(data).digest().bytes())
+}
+
+pub fn handle_ws_request(request: hyper::Request<hyper::Body>, addr: SocketAddr) -> ResponseFuture {
+    let is_upgrade = request.headers().get(hyper::header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false);
+    if crate::moderation::is_banned_ip(&addr.ip()) {
+        return Box::new(future::ok(respond(hyper::http::StatusCode::FORBIDDEN, hyper::Body::empty())));
+    }
+    let key = match request.headers().get(hyper::header::SEC_WEBSOCKET_KEY) {
+        Some(key) if is_upgrade && request.method() == hyper::Method::GET => accept_key(key.as_bytes()),
+        _ => return Box::new(future::ok(respond(hyper::http::StatusCode::BAD_REQUEST, hyper::Body::empty()))),
+    };
+
+    let upgrade = request.into_body().on_upgrade()
+        .map(move |upgraded| serve_websocket(upgraded, addr))
+        .map_err(|err| { print(&format!(">>> WebSocket upgrade error {:?}", err)); });
+    tokio::spawn(upgrade);
+
+    Box::new(future::ok(hyper::Response::builder()
+        .status(hyper::http::StatusCode::SWITCHING_PROTOCOLS)
+        .header(hyper::header::UPGRADE, "websocket")
+        .header(hyper::header::CONNECTION, "Upgrade")
+        .header(hyper::header::SEC_WEBSOCKET_ACCEPT, key.as_str())
+        .body(hyper::Body::empty()).unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+    fn client_frame(first: u8, payload: &[u8]) -> BytesMut {
+        let mut frame = vec![first];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend(&[(payload.len() >> 8) as u8, payload.len() as u8]);
+        }
+        frame.extend(&MASK);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ MASK[i % 4]));
+        BytesMut::from(frame)
+    }
+
+    #[test]
+    fn decodes_masked_text() {
+        let mut src = client_frame(0x80 | OPCODE_TEXT, b"hello");
+        assert_eq!(WsCodec::default().decode(&mut src).unwrap(), Some(Frame::Text("hello".to_string())));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decodes_extended_length() {
+        let text = "x".repeat(300);
+        let mut src = client_frame(0x80 | OPCODE_TEXT, text.as_bytes());
+        assert_eq!(WsCodec::default().decode(&mut src).unwrap(), Some(Frame::Text(text)));
+    }
+
+    #[test]
+    fn waits_for_the_whole_frame() {
+        let frame = client_frame(0x80 | OPCODE_TEXT, b"hello");
+        let mut src = BytesMut::from(&frame[..frame.len() - 1]);
+        assert_eq!(WsCodec::default().decode(&mut src).unwrap(), None);
+        assert_eq!(src.len(), frame.len() - 1);
+    }
+
+    #[test]
+    fn joins_fragments() {
+        let mut codec = WsCodec::default();
+        let mut src = client_frame(OPCODE_TEXT, b"hel");
+        src.extend_from_slice(&client_frame(0x80 | OPCODE_PING, b"p"));
+        src.extend_from_slice(&client_frame(0x80 | OPCODE_CONTINUATION, b"lo"));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Frame::Ping(b"p".to_vec())));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Frame::Text("hello".to_string())));
+    }
+
+    #[test]
+    fn rejects_unmasked_frames() {
+        let mut src = BytesMut::from(vec![0x80 | OPCODE_TEXT, 2, b'h', b'i']);
+        assert!(WsCodec::default().decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn rejects_huge_lengths_without_overflow() {
+        let mut frame = vec![0x80 | OPCODE_TEXT, 0x80 | 127];
+        frame.extend(&[0xFF; 8]);
+        assert!(WsCodec::default().decode(&mut BytesMut::from(frame)).is_err());
+
+        let mut codec = WsCodec::default();
+        let mut src = client_frame(OPCODE_TEXT, &[b'x'; 100]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        let mut frame = vec![0x80 | OPCODE_CONTINUATION, 0x80 | 127];
+        frame.extend(&(MAX_WS_MESSAGE as u64 - 99).to_be_bytes());
+        assert!(codec.decode(&mut BytesMut::from(frame)).is_err());
+    }
+}
@@ -3,9 +3,9 @@ extern crate tokio_io;
 #[macro_use]
 extern crate futures;
 extern crate bytes;
-extern crate ascii;
 extern crate ansi_escapes;
 extern crate console;
+extern crate unicode_width;
 //extern crate hyper;
 #[cfg(unix)]
 extern crate get_if_addrs;
@@ -19,6 +19,7 @@ use tokio::net::TcpStream;
 use tokio::prelude::*;
 use std::string::String;
 use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
 
 pub type Sender = futures::sync::mpsc::Sender<Bytes>;
 pub type Receiver = futures::sync::mpsc::Receiver<Bytes>;
@@ -26,11 +27,75 @@ pub const SERVER_PORT_TEXT: u16 = 49494;
 pub const SERVER_PORT_FILE: u16 = 49495;
 pub const CHANNEL_BUFF_SIZE: usize = 1024usize;
 pub const LINES_PER_TICK: usize = 10;
+/// The one line limit, a server takes lines shorter by `LINE_PREFIX_ROOM` so the `nick: ` it adds still fits for clients.
+pub const MAX_LINE_LENGTH: usize = 64 * 1024;
+pub const LINE_PREFIX_ROOM: usize = 1024;
+/// The longest line a client may send, the server default and the most it can be configured to.
+pub const MAX_SENT_LINE_LENGTH: usize = MAX_LINE_LENGTH - LINE_PREFIX_ROOM;
 
 pub fn bytes_to_str(buff : &bytes::Bytes) -> String {
     (*String::from_utf8_lossy(&buff[..])).to_string()
 }
 
+/// Chat messages travel one per line, so line breaks and backslashes inside a message are escaped.
+pub fn escape_line(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+pub fn unescape_line(line: &str) -> String {
+    let mut result = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('\\') => result.push('\\'),
+            Some(other) => { result.push('\\'); result.push(other); },
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+pub fn percent_encode(text: &str) -> String {
+    let mut result = String::new();
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            result.push(byte as char);
+        } else {
+            result += &format!("%{:02X}", byte);
+        }
+    }
+    result
+}
+
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = if bytes[i] == b'%' && i + 2 < bytes.len() { std::str::from_utf8(&bytes[i + 1..i + 3]).ok() } else { None };
+        match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) => { result.push(byte); i += 3; },
+            None => { result.push(bytes[i]); i += 1; },
+        }
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
 /////////////////////////
 #[cfg(windows)]
 pub fn list_ip() -> Option< std::net::IpAddr>{
@@ -73,33 +138,496 @@ pub fn list_ip() -> Option< std::net::IpAddr>{
 
 ////////////////////
 
+const CTRL_A: char = '\u{1}';
+const CTRL_E: char = '\u{5}';
+const CTRL_G: char = '\u{7}';
+const CTRL_K: char = '\u{b}';
+const CTRL_R: char = '\u{12}';
+const CTRL_U: char = '\u{15}';
+const CTRL_W: char = '\u{17}';
+
+pub const MAX_INPUT_HISTORY: usize = 500;
+/// Input starting with these carries credentials and is never kept in the history.
+pub const SECRET_COMMANDS: &[&str] = &["/op"];
+pub const MAX_SCROLLBACK: usize = 5000;
+pub const UNREAD_MARKER: &str = "----- new messages -----";
+pub const AWAY_AFTER: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Moves to the first column of a 0-based screen row, `AbsoluteXY` takes the column first.
+fn cursor_to_row(row: usize) -> String {
+    ansi_escapes::CursorTo::AbsoluteXY(0, row as u16).to_string()
+}
+
+/// Splits `text` into rows no wider than `cols` terminal cells, ANSI escape sequences take no space.
+pub fn wrap_to_width(text: &str, cols: usize) -> Vec<String> {
+    let mut rows = vec![String::new()];
+    let mut width = 0;
+    let mut in_escape = false;
+    for c in text.chars() {
+        if in_escape || c == '\x1b' {
+            in_escape = c == '\x1b' || !c.is_ascii_alphabetic();
+            rows.last_mut().expect("a row").push(c);
+            continue;
+        }
+        let char_width = unicode_width::UnicodeWidthChar::width(c).unwrap_or(0);
+        if width + char_width > cols.max(1) {
+            rows.push(String::new());
+            width = 0;
+        }
+        rows.last_mut().expect("a row").push(c);
+        width += char_width;
+    }
+    rows
+}
+
+struct HistorySearch {
+    query: String,
+    found: Option<usize>,
+    original: String,
+}
+
+/// Given the input before the cursor returns where the completed word starts and the candidates for it.
+pub type CompleteFn = dyn Fn(&str) -> (usize, Vec<String>) + Send;
+
 pub struct ConsoleBuf {
-    read_bytes: String
+    read_bytes: String,
+    cursor: usize,
+    status: Option<String>,
+    history: Vec<String>,
+    history_pos: Option<usize>,
+    history_draft: String,
+    history_file: Option<std::path::PathBuf>,
+    search: Option<HistorySearch>,
+    completer: Option<Box<CompleteFn>>,
+    tui: bool,
+    status_bar: String,
+    scrollback: VecDeque<String>,
+    scroll: usize,
+    last_key: std::time::Instant,
+    unread: usize,
+    unread_mention: bool,
+    unread_cleared: Option<Box<dyn Fn() + Send>>,
 }
 
 impl ConsoleBuf {
     pub fn new() -> Self {
         print!("{}{}", ansi_escapes::EraseScreen, ansi_escapes::CursorDown(256));
-        ConsoleBuf { read_bytes: String::new() }
+        ConsoleBuf {
+            read_bytes: String::new(),
+            cursor: 0,
+            status: None,
+            history: Vec::new(),
+            history_pos: None,
+            history_draft: String::new(),
+            history_file: None,
+            search: None,
+            completer: None,
+            tui: false,
+            status_bar: String::new(),
+            scrollback: VecDeque::new(),
+            scroll: 0,
+            last_key: std::time::Instant::now(),
+            unread: 0,
+            unread_mention: false,
+            unread_cleared: None,
+        }
+    }
+
+    /// Counts a message the user has not seen yet, after being away a marker line goes above the first one.
+    pub fn add_unread(&mut self, mention: bool) -> (usize, bool) {
+        if self.unread == 0 && self.tui && self.last_key.elapsed() >= AWAY_AFTER {
+            self.cprint(UNREAD_MARKER);
+        }
+        self.unread += 1;
+        self.unread_mention |= mention;
+        (self.unread, self.unread_mention)
+    }
+
+    /// Time since the last key press.
+    pub fn idle(&self) -> std::time::Duration {
+        self.last_key.elapsed()
+    }
+
+    pub fn unread(&self) -> (usize, bool) {
+        (self.unread, self.unread_mention)
+    }
+
+    pub fn set_unread_cleared(&mut self, unread_cleared: Box<dyn Fn() + Send>) {
+        self.unread_cleared = Some(unread_cleared);
     }
 
-    pub fn cprint(&self, msg: &str){
-        print!("{}\r{}\n{}\r{}", ansi_escapes::EraseLine, msg, ansi_escapes::EraseLine, self.read_bytes);
+    fn clear_unread(&mut self) {
+        self.last_key = std::time::Instant::now();
+        if self.unread == 0 {
+            return;
+        }
+        self.unread = 0;
+        self.unread_mention = false;
+        if let Some(pos) = self.scrollback.iter().rposition(|line| line == UNREAD_MARKER) {
+            self.scrollback.remove(pos);
+            self.scroll = self.scroll.min(self.scrollback.len().saturating_sub(1));
+            self.render();
+        }
+        if let Some(unread_cleared) = &self.unread_cleared {
+            unread_cleared();
+        }
+    }
+
+    /// Switches to the full screen layout: message pane, status bar and the input line.
+    pub fn enable_tui(&mut self) {
+        self.tui = true;
+        print!("{}", ansi_escapes::EraseScreen);
+        self.render();
+    }
+
+    /// Leaves the screen as it is with the cursor on the last row, for the shell after exit.
+    pub fn disable_tui(&mut self) {
+        if !self.tui {
+            return;
+        }
+        self.tui = false;
+        let rows = console::Term::stdout().size().0 as usize;
+        print!("{}{}", cursor_to_row(rows.saturating_sub(1)), ansi_escapes::EraseLine);
         let _ = std::io::stdout().flush();
     }
 
-    pub fn handle_input(&mut self, ch: char) -> Option<String>
-    {
-        if ch == ascii::AsciiChar::LineFeed {
-            return Some(std::mem::replace(&mut self.read_bytes, String::new()));
+    pub fn set_status_bar(&mut self, status_bar: String) {
+        self.status_bar = status_bar;
+        if self.tui {
+            self.render();
         }
-        if ch == ascii::AsciiChar::BackSpace.as_char() {
-            self.read_bytes.pop();
-            print!("{}\r{}", ansi_escapes::EraseLine, self.read_bytes);
+    }
+
+    fn pane_height(rows: usize) -> usize {
+        rows.saturating_sub(2).max(1)
+    }
+
+    fn render(&self) {
+        let (rows, cols) = console::Term::stdout().size();
+        let (rows, cols) = (rows as usize, cols as usize);
+        let pane = Self::pane_height(rows);
+        let end = self.scrollback.len().saturating_sub(self.scroll);
+        let mut visible: Vec<String> = Vec::with_capacity(pane);
+        for line in self.scrollback.iter().take(end).rev() {
+            let mut wrapped = wrap_to_width(line, cols);
+            while let Some(row) = wrapped.pop() {
+                visible.push(row);
+            }
+            if visible.len() >= pane {
+                break;
+            }
+        }
+        visible.truncate(pane);
+        visible.reverse();
+
+        let padding = pane - visible.len();
+        for row in 0..pane {
+            let text = if row < padding { "" } else { visible[row - padding].as_str() };
+            print!("{}{}{}\x1b[0m", cursor_to_row(row), ansi_escapes::EraseLine, text);
+        }
+        let mut bar = format!(" {}", self.status_bar);
+        if let Some(status) = &self.status {
+            bar = format!("{} | {}", bar, status);
+        }
+        if self.scroll > 0 {
+            bar = format!("{} | scrolled back {} line(s)", bar, self.scroll);
+        }
+        let bar: String = wrap_to_width(&bar, cols).into_iter().next().unwrap_or_default();
+        let bar_padding = cols.saturating_sub(unicode_width::UnicodeWidthStr::width(bar.as_str()));
+        print!("{}{}{}", cursor_to_row(pane), ansi_escapes::EraseLine,
+            console::style(format!("{}{}", bar, " ".repeat(bar_padding))).reverse());
+        self.render_input(pane + 1, cols);
+    }
+
+    fn input_view(&self) -> (String, usize) {
+        if let Some(search) = &self.search {
+            let found = search.found.map(|i| self.history[i].as_str()).unwrap_or("");
+            let view = format!("(reverse-i-search)'{}': {}", search.query, found);
+            let width = unicode_width::UnicodeWidthStr::width(view.as_str());
+            return (view, width);
+        }
+        let before_cursor = unicode_width::UnicodeWidthStr::width(&self.read_bytes[..self.cursor]);
+        (self.read_bytes.clone(), before_cursor)
+    }
+
+    fn render_input(&self, row: usize, cols: usize) {
+        let (view, mut cursor) = self.input_view();
+        let mut view = view.as_str();
+        while cursor >= cols && !view.is_empty() {
+            let first = view.chars().next().expect("not empty");
+            cursor -= unicode_width::UnicodeWidthChar::width(first).unwrap_or(0);
+            view = &view[first.len_utf8()..];
+        }
+        let shown: String = wrap_to_width(view, cols).into_iter().next().unwrap_or_default();
+        print!("{}{}{}{}", cursor_to_row(row), ansi_escapes::EraseLine, shown,
+            ansi_escapes::CursorTo::AbsoluteX(cursor as u16));
+        let _ = std::io::stdout().flush();
+    }
+
+    fn scroll_by(&mut self, page_up: bool) {
+        let page = Self::pane_height(console::Term::stdout().size().0 as usize);
+        self.scroll = if page_up {
+            (self.scroll + page).min(self.scrollback.len().saturating_sub(1))
         } else {
-            print!("{}", ch);
-            self.read_bytes.push(ch);
+            self.scroll.saturating_sub(page)
+        };
+        self.render();
+    }
+
+    fn erase_status(&self) {
+        if self.status.is_some() {
+            print!("{}{}", ansi_escapes::EraseLine, ansi_escapes::CursorUp(1));
+        }
+    }
+
+    fn print_tail(&self) {
+        if self.tui {
+            let (rows, cols) = console::Term::stdout().size();
+            self.render_input(Self::pane_height(rows as usize) + 1, cols as usize);
+            return;
+        }
+        if let Some(status) = &self.status {
+            print!("{}\r{}\n", ansi_escapes::EraseLine, status);
+        }
+        let (view, before_cursor) = self.input_view();
+        print!("{}\r{}", ansi_escapes::EraseLine, view);
+        let after_cursor = unicode_width::UnicodeWidthStr::width(view.as_str()) - before_cursor;
+        if after_cursor > 0 {
+            print!("{}", ansi_escapes::CursorBackward(after_cursor as u16));
+        }
+        let _ = std::io::stdout().flush();
+    }
+
+    pub fn cprint(&mut self, msg: &str){
+        if self.tui {
+            for (i, line) in msg.split('\n').enumerate() {
+                let indent = if i == 0 { "" } else { "  | " };
+                self.scrollback.push_back(format!("{}{}", indent, line.trim_end_matches('\r')));
+                if self.scroll > 0 {
+                    self.scroll += 1;
+                }
+            }
+            let excess = self.scrollback.len().saturating_sub(MAX_SCROLLBACK);
+            self.scrollback.drain(..excess);
+            self.scroll = self.scroll.min(self.scrollback.len().saturating_sub(1));
+            self.render();
+            return;
+        }
+        self.erase_status();
+        for (i, line) in msg.split('\n').enumerate() {
+            let indent = if i == 0 { "" } else { "  | " };
+            print!("{}\r{}{}\n", ansi_escapes::EraseLine, indent, line.trim_end_matches('\r'));
+        }
+        self.print_tail();
+    }
+
+    pub fn set_status(&mut self, status: Option<String>) {
+        if self.tui {
+            self.status = status;
+            self.render();
+            return;
+        }
+        self.erase_status();
+        self.status = status;
+        self.print_tail();
+    }
+
+    /// Loads earlier input lines and keeps saving new ones to `path`.
+    pub fn set_history_file(&mut self, path: std::path::PathBuf) {
+        if let Ok(content) = std::fs::read_to_string(&path) {
+            self.history = content.lines().map(|line| line.to_string()).collect();
+            let excess = self.history.len().saturating_sub(MAX_INPUT_HISTORY);
+            self.history.drain(..excess);
+            if excess > 0 {
+                let _ = std::fs::write(&path, format!("{}\n", self.history.join("\n")));
+            }
+        }
+        self.history_file = Some(path);
+    }
+
+    fn remember(&mut self, line: &str) {
+        self.history_pos = None;
+        if line.trim().is_empty() || self.history.last().map(|last| last == line).unwrap_or(false) {
+            return;
+        }
+        if SECRET_COMMANDS.iter().any(|command| line == *command || line.starts_with(&format!("{} ", command))) {
+            return;
+        }
+        self.history.push(line.to_string());
+        if self.history.len() > MAX_INPUT_HISTORY {
+            self.history.remove(0);
+        }
+        if let Some(path) = &self.history_file {
+            let appended = std::fs::OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut file| writeln!(file, "{}", line));
+            if let Err(e) = appended {
+                self.status = Some(format!(">>> Cannot save input history: {}", e));
+            }
+        }
+    }
+
+    fn recall(&mut self, pos: Option<usize>) {
+        if self.history_pos.is_none() {
+            self.history_draft = self.read_bytes.clone();
+        }
+        self.history_pos = pos;
+        self.read_bytes = match pos {
+            Some(i) => self.history[i].clone(),
+            None => std::mem::take(&mut self.history_draft),
+        };
+        self.cursor = self.read_bytes.len();
+    }
+
+    pub fn set_completer(&mut self, completer: Box<CompleteFn>) {
+        self.completer = Some(completer);
+    }
+
+    fn complete(&mut self) {
+        let (start, candidates) = match &self.completer {
+            Some(completer) => completer(&self.read_bytes[..self.cursor]),
+            None => return,
+        };
+        let first = match candidates.first() {
+            Some(first) => first.clone(),
+            None => return,
+        };
+        let common = candidates.iter().skip(1).fold(first, |common, candidate| {
+            common.chars().zip(candidate.chars())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect()
+        });
+        if candidates.len() > 1 && common.len() <= self.cursor - start {
+            let list = candidates.join("  ");
+            self.cprint(&list);
+            return;
         }
+        self.read_bytes.replace_range(start..self.cursor, &common);
+        self.cursor = start + common.len();
+    }
+
+    fn find_in_history(&self, query: &str, before: usize) -> Option<usize> {
+        if query.is_empty() {
+            return None;
+        }
+        (0..before).rev().find(|i| self.history[*i].contains(query))
+    }
+
+    fn handle_search_key(&mut self, key: console::Key) -> Option<String> {
+        let mut search = self.search.take().expect("active search");
+        match key {
+            console::Key::Char(CTRL_R) => {
+                let before = search.found.unwrap_or(self.history.len());
+                search.found = self.find_in_history(&search.query, before).or(search.found);
+            },
+            console::Key::Char(ch) if !ch.is_control() => {
+                search.query.push(ch);
+                let before = search.found.map(|i| i + 1).unwrap_or(self.history.len());
+                search.found = self.find_in_history(&search.query, before);
+            },
+            console::Key::Backspace => {
+                search.query.pop();
+                search.found = self.find_in_history(&search.query, self.history.len());
+            },
+            console::Key::Escape | console::Key::Char(CTRL_G) => {
+                self.read_bytes = search.original;
+                self.cursor = self.read_bytes.len();
+                self.print_tail();
+                return None;
+            },
+            key => {
+                self.read_bytes = match search.found {
+                    Some(i) => self.history[i].clone(),
+                    None => search.original,
+                };
+                self.cursor = self.read_bytes.len();
+                if key == console::Key::Enter {
+                    return self.handle_key(key);
+                }
+                self.print_tail();
+                return None;
+            },
+        }
+        self.search = Some(search);
+        self.print_tail();
+        None
+    }
+
+    fn prev_boundary(&self, pos: usize) -> usize {
+        self.read_bytes[..pos].char_indices().next_back().map(|(i, _)| i).unwrap_or(0)
+    }
+
+    fn next_boundary(&self, pos: usize) -> usize {
+        self.read_bytes[pos..].chars().next().map(|c| pos + c.len_utf8()).unwrap_or(pos)
+    }
+
+    fn word_start(&self) -> usize {
+        let before = self.read_bytes[..self.cursor].trim_end();
+        before.char_indices()
+            .filter(|(_, c)| c.is_whitespace())
+            .map(|(i, c)| i + c.len_utf8())
+            .next_back()
+            .unwrap_or(0)
+    }
+
+    pub fn handle_key(&mut self, key: console::Key) -> Option<String>
+    {
+        self.clear_unread();
+        if self.search.is_some() {
+            return self.handle_search_key(key);
+        }
+        match key {
+            console::Key::Enter => {
+                self.cursor = 0;
+                let line = std::mem::take(&mut self.read_bytes);
+                self.remember(&line);
+                self.print_tail();
+                return Some(line);
+            },
+            console::Key::ArrowUp if !self.history.is_empty() => {
+                let pos = self.history_pos.map(|i| i.saturating_sub(1)).unwrap_or(self.history.len() - 1);
+                self.recall(Some(pos));
+            },
+            console::Key::ArrowDown if self.history_pos.is_some() => {
+                let pos = self.history_pos.map(|i| i + 1).filter(|i| *i < self.history.len());
+                self.recall(pos);
+            },
+            console::Key::Tab => self.complete(),
+            console::Key::PageUp if self.tui => self.scroll_by(true),
+            console::Key::PageDown if self.tui => self.scroll_by(false),
+            console::Key::Char(CTRL_R) => {
+                self.search = Some(HistorySearch { query: String::new(), found: None, original: self.read_bytes.clone() });
+            },
+            console::Key::Backspace if self.cursor > 0 => {
+                let start = self.prev_boundary(self.cursor);
+                self.read_bytes.replace_range(start..self.cursor, "");
+                self.cursor = start;
+            },
+            console::Key::Del => {
+                let end = self.next_boundary(self.cursor);
+                self.read_bytes.replace_range(self.cursor..end, "");
+            },
+            console::Key::ArrowLeft => self.cursor = self.prev_boundary(self.cursor),
+            console::Key::ArrowRight => self.cursor = self.next_boundary(self.cursor),
+            console::Key::Home | console::Key::Char(CTRL_A) => self.cursor = 0,
+            console::Key::End | console::Key::Char(CTRL_E) => self.cursor = self.read_bytes.len(),
+            console::Key::Char(CTRL_U) => {
+                self.read_bytes.replace_range(..self.cursor, "");
+                self.cursor = 0;
+            },
+            console::Key::Char(CTRL_K) => self.read_bytes.truncate(self.cursor),
+            console::Key::Char(CTRL_W) => {
+                let start = self.word_start();
+                self.read_bytes.replace_range(start..self.cursor, "");
+                self.cursor = start;
+            },
+            console::Key::Char(ch) if !ch.is_control() => {
+                self.read_bytes.insert(self.cursor, ch);
+                self.cursor += ch.len_utf8();
+            },
+            _ => return None,
+        }
+        self.print_tail();
         None
     }
 }
@@ -110,6 +638,7 @@ pub struct LinesTcp {
     pub socket: TcpStream,
     rd: bytes::BytesMut,
     wr: bytes::BytesMut,
+    max_line: usize,
 }
 
 impl LinesTcp {
@@ -118,9 +647,12 @@ impl LinesTcp {
             socket,
             rd: bytes::BytesMut::new(),
             wr: bytes::BytesMut::new(),
+            max_line: MAX_LINE_LENGTH,
         }
     }
 
+    pub fn max_line(&self) -> usize { self.max_line }
+
     pub fn buffer(&mut self, line: &[u8]) {
         self.wr.reserve(line.len());
         self.wr.put(line);
@@ -138,6 +670,9 @@ impl LinesTcp {
 
     fn fill_read_buf(&mut self) -> Poll<(), io::Error> {
         loop {
+            if self.rd.len() > self.max_line + 2 {
+                return Ok(Async::NotReady); // poll() splits off a line or fails on the oversized one
+            }
             self.rd.reserve(1024);
             let n = try_ready!(self.socket.read_buf(&mut self.rd));
             if n == 0 {
@@ -157,6 +692,9 @@ impl Stream for LinesTcp {
             .find(|&(_, bytes)| bytes == b"\r\n")
             .map(|(i, _)| i);
 
+        if pos.unwrap_or_else(|| self.rd.len()) > self.max_line {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("line longer than {} bytes", self.max_line)));
+        }
         if let Some(pos) = pos {
             let mut line = self.rd.split_to(pos + 2);
             line.split_off(pos);
@@ -192,47 +730,33 @@ impl Stream for InputReader {
     type Error = io::Error;
     fn poll(&mut self) -> Poll<Option<String>, io::Error> {
         let _ = io::stdout().flush();
-        let ch = self.terminal.read_char()?;
+        let key = self.terminal.read_key()?;
         task::current().notify();
-        if let Some(line) = self.consolebuf.lock().unwrap().handle_input(ch) {
+        if let Some(line) = self.consolebuf.lock().unwrap().handle_key(key) {
             return Ok(Async::Ready(Some(line)));
         }
         Ok(Async::NotReady)
     }
 }
 
-/////////////////////////////////////////////////////////
+///////////////////////////////////////////////////////////////////
 
-pub fn parse_send_file(msg : &String) -> Option<String> {
-    let start_pattern = ":send \"";
-    if !msg.starts_with(start_pattern) {
-        return None;
-    } 
-    if !msg.ends_with('\"') {
-        return None;
-    } 
-    if !(msg.len() > 8) {
-        return None;
-    }
-    let mut result = msg.replace(start_pattern, "");
-    result.pop();
-    Some(result)
+pub const CONTROL_PREFIX: char = '\u{1}';
+
+/// Marks a name in the `MEMBERS` control line whose user went idle, clients report it with `AWAY on|off`.
+pub const AWAY_SUFFIX: &str = "+away";
+
+pub fn control_line(fields: &[&str]) -> String {
+    format!("{}{}", CONTROL_PREFIX, fields.join(" "))
 }
 
-pub fn parse_accept_file(msg : &String) -> Option<(String, String)> {
-    let start_pattern = ":send \"";
-    if msg.starts_with(start_pattern) && msg.len() > 8 {
-        let temp = msg.replace(start_pattern, "");
-        let v: Vec<&str> = temp.split("\" ").collect();
-        if 2 == v.len() {
-            return Some((v[0].to_string(), v[1].to_string()));
-        }
+pub fn parse_control(line: &str, max_fields: usize) -> Option<Vec<&str>> {
+    if !line.starts_with(CONTROL_PREFIX) {
+        return None;
     }
-    None
+    Some(line[CONTROL_PREFIX.len_utf8()..].splitn(max_fields, ' ').collect())
 }
 
-///////////////////////////////////////////////////////////////////
-
 pub fn pass_line(sender: &mut Sender, line : String) -> Result<(), futures::sync::mpsc::TrySendError<Bytes>>{
     let mut outmsg = BytesMut::from(line);
     outmsg.extend_from_slice(b"\r\n");
@@ -242,12 +766,124 @@ pub fn pass_line(sender: &mut Sender, line : String) -> Result<(), futures::sync
 //////////////////////////////////////////////////////////////////
 
 pub type SafeConsole = Arc<Mutex<ConsoleBuf>>;
+pub const FLOOD_WARNING: &str = ">>> You are sending too fast, slow down or you will be disconnected";
+pub const FLOOD_DISCONNECT: &str = ">>> Disconnected for flooding";
+
+/// Thresholds of a per connection token bucket, a rate of 0 disables that bucket.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    pub lines_per_second: f64,
+    pub line_burst: f64,
+    pub bytes_per_second: f64,
+    pub byte_burst: f64,
+    pub warn_after: u32,
+    pub disconnect_after: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RateVerdict { Allow, Warn, Disconnect }
+
+pub struct RateLimiter {
+    limit: RateLimit,
+    lines: f64,
+    bytes: f64,
+    updated: std::time::Instant,
+    strikes: u32,
+    delay: Option<tokio::timer::Delay>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            lines: limit.line_burst,
+            bytes: limit.byte_burst,
+            limit,
+            updated: std::time::Instant::now(),
+            strikes: 0,
+            delay: None,
+        }
+    }
+
+    fn refill(&mut self, now: std::time::Instant) {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.lines = (self.lines + elapsed * self.limit.lines_per_second).min(self.limit.line_burst);
+        self.bytes = (self.bytes + elapsed * self.limit.bytes_per_second).min(self.limit.byte_burst);
+        self.updated = now;
+        if self.lines >= self.limit.line_burst && self.bytes >= self.limit.byte_burst {
+            self.strikes = 0;
+        }
+    }
+
+    /// Not ready while the connection is being slowed down.
+    pub fn poll_ready(&mut self) -> Poll<(), io::Error> {
+        if let Some(ref mut delay) = self.delay {
+            try_ready!(delay.poll().map_err(io::Error::other));
+        }
+        self.delay = None;
+        Ok(Async::Ready(()))
+    }
+
+    /// Charges one line only when the buckets hold enough right now, for requests that cannot be slowed down.
+    pub fn try_take(&mut self, line_bytes: usize) -> bool {
+        self.refill(std::time::Instant::now());
+        let lines = self.limit.lines_per_second <= 0.0 || self.lines >= 1.0;
+        let bytes = self.limit.bytes_per_second <= 0.0 || self.bytes >= line_bytes as f64;
+        if !lines || !bytes {
+            return false;
+        }
+        if self.limit.lines_per_second > 0.0 {
+            self.lines -= 1.0;
+        }
+        if self.limit.bytes_per_second > 0.0 {
+            self.bytes -= line_bytes as f64;
+        }
+        true
+    }
+
+    /// True once both buckets are full again, such a limiter can be dropped and made anew.
+    pub fn is_rested(&mut self) -> bool {
+        self.refill(std::time::Instant::now());
+        self.lines >= self.limit.line_burst && self.bytes >= self.limit.byte_burst
+    }
+
+    /// Charges one line, past the burst the connection is delayed and after enough strikes warned, then dropped.
+    pub fn take(&mut self, line_bytes: usize) -> RateVerdict {
+        let now = std::time::Instant::now();
+        self.refill(now);
+        let mut wait: f64 = 0.0;
+        if self.limit.lines_per_second > 0.0 {
+            self.lines -= 1.0;
+            wait = wait.max(-self.lines / self.limit.lines_per_second);
+        }
+        if self.limit.bytes_per_second > 0.0 {
+            self.bytes -= line_bytes as f64;
+            wait = wait.max(-self.bytes / self.limit.bytes_per_second);
+        }
+        if wait <= 0.0 {
+            return RateVerdict::Allow;
+        }
+        self.strikes += 1;
+        self.delay = Some(tokio::timer::Delay::new(now + std::time::Duration::from_millis((wait * 1000.0) as u64)));
+        if self.strikes >= self.limit.disconnect_after {
+            RateVerdict::Disconnect
+        } else if self.strikes == self.limit.warn_after {
+            RateVerdict::Warn
+        } else {
+            RateVerdict::Allow
+        }
+    }
+}
+
 pub type HandleReceivedFn = dyn Fn(&TextConnection, String)->() + Send;
 
 pub struct TextConnection {
     pub lines: LinesTcp,
     pub receiver: Receiver,
     pub callback: Box<HandleReceivedFn>,
+    pub limiter: Option<RateLimiter>,
+    /// Set once a disconnect notice is buffered, the connection ends with it after the notice is flushed.
+    closing: Option<io::Error>,
 }
 
 impl Future for TextConnection {
@@ -255,6 +891,11 @@ impl Future for TextConnection {
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<(), io::Error> {
+        if self.closing.is_some() {
+            try_ready!(self.lines.poll_flush());
+            return Err(self.closing.take().expect("closing reason"));
+        }
+        let mut receiver_closed = false;
         for i in 0..LINES_PER_TICK {
             match self.receiver.poll() {
                 Ok(Async::Ready(Some(v))) => {
@@ -263,20 +904,44 @@ impl Future for TextConnection {
                         task::current().notify();
                     }
                 },
+                Ok(Async::Ready(None)) => { receiver_closed = true; break; },
                 Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "receiver poll failed")),
                 _ => break,
             }
         }
 
-        let _ = self.lines.poll_flush()?;
+        let flushed = self.lines.poll_flush()?.is_ready();
+        if receiver_closed && flushed {
+            return Ok(Async::Ready(()));
+        }
         loop {
+            if let Some(ref mut limiter) = self.limiter {
+                try_ready!(limiter.poll_ready());
+            }
             match self.lines.poll() {
                 Ok(Async::Ready(Some(message))) => {
+                    let verdict = match self.limiter {
+                        Some(ref mut limiter) => limiter.take(message.len()),
+                        None => RateVerdict::Allow,
+                    };
+                    match verdict {
+                        RateVerdict::Allow => {},
+                        RateVerdict::Warn => self.lines.buffer(format!("{}\r\n", FLOOD_WARNING).as_bytes()),
+                        RateVerdict::Disconnect => {
+                            self.lines.buffer(format!("{}\r\n", FLOOD_DISCONNECT).as_bytes());
+                            return self.close(io::Error::new(io::ErrorKind::ConnectionAborted, "flood limit exceeded"));
+                        },
+                    }
                     let message = message.freeze();
                     let message_str = bytes_to_str(&message);
                     (self.callback)(&self, message_str);
                 },
                 Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                    let notice = format!(">>> Line too long, the limit is {} bytes, disconnected\r\n", self.lines.max_line());
+                    self.lines.buffer(notice.as_bytes());
+                    return self.close(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+                },
                 Ok(Async::Ready(None)) | Err(_) => return Ok(Async::Ready(())),
             }
         }
@@ -288,9 +953,26 @@ impl TextConnection {
         TextConnection {
             lines: LinesTcp::new(socket),
             receiver,
-            callback
+            callback,
+            limiter: None,
+            closing: None,
         }
     }
+
+    fn close(&mut self, reason: io::Error) -> Poll<(), io::Error> {
+        self.closing = Some(reason);
+        self.poll()
+    }
+
+    pub fn with_max_line_length(mut self, max_line: usize) -> TextConnection {
+        self.lines.max_line = max_line;
+        self
+    }
+
+    pub fn with_rate_limit(mut self, limit: RateLimit) -> TextConnection {
+        self.limiter = Some(RateLimiter::new(limit));
+        self
+    }
 }
 
 ////////////////////////////////////////////////////////////////// HYPER
@@ -300,4 +982,98 @@ pub fn read_file_blocking(file_path: &std::path::Path) -> std::io::Result<Vec<u8
     let mut data = Vec::new();
     file.read_to_end(&mut data)?;
     return Ok(data);
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_to_row_moves_to_first_column() {
+        assert_eq!(cursor_to_row(0), "\x1b[1;1H");
+        assert_eq!(cursor_to_row(5), "\x1b[6;1H");
+    }
+
+    #[test]
+    fn escape_line_keeps_messages_on_one_line() {
+        assert_eq!(escape_line("a\nb\r\\c"), "a\\nb\\r\\\\c");
+        assert!(!escape_line("two\nlines").contains('\n'));
+        for text in &["plain", "multi\nline\r\n", "back\\slash\\n", "\\", "ends with \\"] {
+            assert_eq!(unescape_line(&escape_line(text)), *text);
+        }
+    }
+
+    #[test]
+    fn unescape_line_keeps_unknown_escapes() {
+        assert_eq!(unescape_line("C:\\dir\\x"), "C:\\dir\\x");
+        assert_eq!(unescape_line("trailing\\"), "trailing\\");
+    }
+
+    #[test]
+    fn percent_encoding_round_trips() {
+        assert_eq!(percent_encode("a b/ż.txt"), "a%20b%2F%C5%BC.txt");
+        for text in &["plain-name_1.~", "spaces and %", "zażółć", ""] {
+            assert_eq!(percent_decode(&percent_encode(text)), *text);
+        }
+    }
+
+    #[test]
+    fn percent_decode_keeps_broken_sequences() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    }
+
+    #[test]
+    fn parse_control_splits_fields() {
+        let line = control_line(&["TO", "bob", "MSG hello there"]);
+        assert_eq!(parse_control(&line, 3), Some(vec!["TO", "bob", "MSG hello there"]));
+        assert_eq!(parse_control(&line, usize::MAX), Some(vec!["TO", "bob", "MSG", "hello", "there"]));
+        assert_eq!(parse_control("TO bob", 3), None);
+    }
+
+    #[test]
+    fn wrap_to_width_counts_cells() {
+        assert_eq!(wrap_to_width("abcdef", 4), vec!["abcd", "ef"]);
+        assert_eq!(wrap_to_width("", 4), vec![""]);
+        assert_eq!(wrap_to_width("日本語", 4), vec!["日本", "語"]);
+        assert_eq!(wrap_to_width("\x1b[31mabcd\x1b[0m", 4), vec!["\x1b[31mabcd\x1b[0m"]);
+    }
+
+    fn strict_limit() -> RateLimit {
+        RateLimit { lines_per_second: 1.0, line_burst: 2.0, bytes_per_second: 0.0, byte_burst: 0.0, warn_after: 1, disconnect_after: 3 }
+    }
+
+    #[test]
+    fn rate_limiter_allows_the_burst() {
+        let mut limiter = RateLimiter::new(strict_limit());
+        assert_eq!(limiter.take(10), RateVerdict::Allow);
+        assert_eq!(limiter.take(10), RateVerdict::Allow);
+    }
+
+    #[test]
+    fn rate_limiter_warns_then_disconnects() {
+        let mut limiter = RateLimiter::new(strict_limit());
+        limiter.take(10);
+        limiter.take(10);
+        assert_eq!(limiter.take(10), RateVerdict::Warn);
+        assert_eq!(limiter.take(10), RateVerdict::Allow);
+        assert_eq!(limiter.take(10), RateVerdict::Disconnect);
+    }
+
+    #[test]
+    fn rate_limiter_counts_bytes() {
+        let limit = RateLimit { lines_per_second: 0.0, line_burst: 0.0, bytes_per_second: 100.0, byte_burst: 100.0, warn_after: 1, disconnect_after: 2 };
+        let mut limiter = RateLimiter::new(limit);
+        assert_eq!(limiter.take(100), RateVerdict::Allow);
+        assert_eq!(limiter.take(1), RateVerdict::Warn);
+    }
+
+    #[test]
+    fn rate_limiter_try_take_refuses_past_the_burst() {
+        let mut limiter = RateLimiter::new(strict_limit());
+        assert!(limiter.try_take(10));
+        assert!(limiter.try_take(10));
+        assert!(!limiter.try_take(10));
+        assert!(!limiter.is_rested());
+    }
+}